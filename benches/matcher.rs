@@ -0,0 +1,127 @@
+//! Compares the segment trie with the `RegexSet` and per-route `Regex` it replaced, on a few
+//! thousand routes. Both sides find the most specific matching pattern by the precedence of
+//! `Pattern`'s `Ord` and extract its parameters. Run with `cargo bench`.
+
+extern crate regex;
+extern crate senya;
+
+use regex::{Regex, RegexSet};
+use senya::pattern::{Pattern, PatternSet};
+use std::time::{Duration, Instant};
+
+const ROUTES: usize = 1000;
+const ROUNDS: usize = 20;
+
+/// Four routes for each `i`, as patterns and as the regexes the old matcher compiled them to.
+fn routes() -> Vec<(String, String)> {
+    let mut out = Vec::new();
+    for i in 0..ROUTES {
+        out.push((
+            format!("/api/v1/items{}", i),
+            format!("(?-u:^api/v1/items{}$)", i),
+        ));
+        out.push((
+            format!("/api/v1/items{}/{{id}}", i),
+            format!("(?-u:^api/v1/items{}/([^/]+)$)", i),
+        ));
+        out.push((
+            format!("/api/v1/items{}/{{id}}/tags/{{tag}}", i),
+            format!("(?-u:^api/v1/items{}/([^/]+)/tags/([^/]+)$)", i),
+        ));
+        out.push((
+            format!("/static{}/:path", i),
+            format!(r"(?-u:^static{}/([^\s]*)$)", i),
+        ));
+    }
+    out
+}
+
+fn paths() -> Vec<String> {
+    let mut out = Vec::new();
+    for i in (0..ROUTES).filter(|i| i % 7 == 0) {
+        out.push(format!("/api/v1/items{}", i));
+        out.push(format!("/api/v1/items{}/42", i));
+        out.push(format!("/api/v1/items{}/42/tags/rust", i));
+        out.push(format!("/static{}/css/main.css", i));
+        out.push(format!("/missing{}/42", i));
+    }
+    out
+}
+
+fn per_lookup(d: Duration, lookups: usize) -> f64 {
+    (d.as_secs() as f64 * 1e9 + d.subsec_nanos() as f64) / lookups as f64
+}
+
+fn main() {
+    let routes = routes();
+    let paths = paths();
+    let lookups = paths.len() * ROUNDS;
+    let patterns = routes
+        .iter()
+        .map(|r| r.0.parse::<Pattern>().unwrap())
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    let mut set = PatternSet::new();
+    for pat in &patterns {
+        set.insert(pat.clone()).unwrap();
+    }
+    let set = set.compile();
+    let build = start.elapsed();
+    let start = Instant::now();
+    let mut found = 0;
+    for _ in 0..ROUNDS {
+        for path in &paths {
+            if let Some(c) = set.find(path) {
+                let params = c.params.iter().map(|&(_, v)| v.unwrap()).collect::<Vec<_>>();
+                found += params.iter().all(|p| !p.is_empty()) as usize;
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "trie:  {} routes built in {:?}, {:.0} ns/lookup ({} found)",
+        routes.len(),
+        build,
+        per_lookup(elapsed, lookups),
+        found
+    );
+
+    let start = Instant::now();
+    let re_set = RegexSet::new(routes.iter().map(|r| &r.1)).unwrap();
+    let res = routes
+        .iter()
+        .map(|r| Regex::new(&r.1).unwrap())
+        .collect::<Vec<_>>();
+    // the position of each route in precedence order
+    let mut order = (0..patterns.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| patterns[a].cmp(&patterns[b]));
+    let mut rank = vec![0; order.len()];
+    for (r, &i) in order.iter().enumerate() {
+        rank[i] = r;
+    }
+    let build = start.elapsed();
+    let start = Instant::now();
+    let mut found = 0;
+    for _ in 0..ROUNDS {
+        for path in &paths {
+            let path = &path[1..];
+            if let Some(i) = re_set.matches(path).into_iter().min_by_key(|&i| rank[i]) {
+                let caps = res[i].captures(path).unwrap();
+                let params = caps.iter()
+                    .skip(1)
+                    .map(|m| m.unwrap().as_str())
+                    .collect::<Vec<_>>();
+                found += params.iter().all(|p| !p.is_empty()) as usize;
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "regex: {} routes built in {:?}, {:.0} ns/lookup ({} found)",
+        routes.len(),
+        build,
+        per_lookup(elapsed, lookups),
+        found
+    );
+}
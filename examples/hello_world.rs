@@ -14,7 +14,7 @@ fn main() {
             Method::Get,
             // Matches everything under `/`.
             "/:path",
-            "hello world!",
+            |_: Ctx| -> io::Result<Response> { Ok(Response::new().with_body("hello world!")) },
         )
         .route(
             Method::Get,
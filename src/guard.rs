@@ -0,0 +1,173 @@
+//! Request predicates used to choose between routes sharing a method and a pattern.
+
+use hyper::Request;
+use hyper::header;
+use hyper::mime::Mime;
+use std::borrow::Cow;
+use url::form_urlencoded;
+
+pub trait Guard: 'static {
+    fn check(&self, req: &Request) -> bool;
+}
+
+impl<F> Guard for F
+where
+    F: 'static + Fn(&Request) -> bool,
+{
+    #[inline]
+    fn check(&self, req: &Request) -> bool {
+        (self)(req)
+    }
+}
+
+fn mime_matches(pat: &Mime, mime: &Mime) -> bool {
+    (pat.type_() == "*" || pat.type_() == mime.type_())
+        && (pat.subtype() == "*" || pat.subtype() == mime.subtype())
+}
+
+/// Passes if the request body has the given media type. Parameters such as `charset` are ignored.
+#[derive(Debug, Clone)]
+pub struct ContentType(pub Mime);
+
+impl Guard for ContentType {
+    fn check(&self, req: &Request) -> bool {
+        match req.headers().get::<header::ContentType>() {
+            Some(ct) => mime_matches(&self.0, &ct.0),
+            None => false,
+        }
+    }
+}
+
+/// Passes if the client accepts the given media type. A missing `Accept` header accepts anything.
+#[derive(Debug, Clone)]
+pub struct Accept(pub Mime);
+
+impl Guard for Accept {
+    fn check(&self, req: &Request) -> bool {
+        match req.headers().get::<header::Accept>() {
+            Some(accept) => accept
+                .iter()
+                .any(|qi| qi.quality != header::q(0) && mime_matches(&qi.item, &self.0)),
+            None => true,
+        }
+    }
+}
+
+/// Passes if the header is present, and has the given value if one is specified.
+#[derive(Debug, Clone)]
+pub struct Header {
+    name: Cow<'static, str>,
+    value: Option<Vec<u8>>,
+}
+
+impl Header {
+    pub fn present<N: Into<Cow<'static, str>>>(name: N) -> Self {
+        Header {
+            name: name.into(),
+            value: None,
+        }
+    }
+
+    pub fn equals<N: Into<Cow<'static, str>>, V: Into<Vec<u8>>>(name: N, value: V) -> Self {
+        Header {
+            name: name.into(),
+            value: Some(value.into()),
+        }
+    }
+}
+
+impl Guard for Header {
+    fn check(&self, req: &Request) -> bool {
+        match (req.headers().get_raw(&self.name), self.value.as_ref()) {
+            (Some(raw), Some(value)) => raw.iter().any(|line| line == &value[..]),
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+/// Passes if the query parameter is present, and has the given value if one is specified.
+#[derive(Debug, Clone)]
+pub struct Query {
+    name: Cow<'static, str>,
+    value: Option<Cow<'static, str>>,
+}
+
+impl Query {
+    pub fn present<N: Into<Cow<'static, str>>>(name: N) -> Self {
+        Query {
+            name: name.into(),
+            value: None,
+        }
+    }
+
+    pub fn equals<N, V>(name: N, value: V) -> Self
+    where
+        N: Into<Cow<'static, str>>,
+        V: Into<Cow<'static, str>>,
+    {
+        Query {
+            name: name.into(),
+            value: Some(value.into()),
+        }
+    }
+}
+
+impl Guard for Query {
+    fn check(&self, req: &Request) -> bool {
+        let query = match req.query() {
+            Some(q) => q,
+            None => return false,
+        };
+        form_urlencoded::parse(query.as_bytes()).any(|(k, v)| {
+            k == self.name && self.value.as_ref().map_or(true, |value| v == *value)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::Method;
+    use hyper::mime;
+
+    fn request(uri: &str) -> Request {
+        Request::new(Method::Get, uri.parse().unwrap())
+    }
+
+    #[test]
+    fn content_type() {
+        let mut req = request("/");
+        assert!(!ContentType(mime::APPLICATION_JSON).check(&req));
+        req.headers_mut()
+            .set(header::ContentType(mime::TEXT_PLAIN_UTF_8));
+        assert!(ContentType(mime::TEXT_PLAIN).check(&req));
+        assert!(ContentType(mime::TEXT_STAR).check(&req));
+        assert!(!ContentType(mime::APPLICATION_JSON).check(&req));
+    }
+
+    #[test]
+    fn accept() {
+        let mut req = request("/");
+        assert!(Accept(mime::APPLICATION_JSON).check(&req));
+        req.headers_mut().set_raw("Accept", "text/html, application/*;q=0.5");
+        assert!(Accept(mime::TEXT_HTML).check(&req));
+        assert!(Accept(mime::APPLICATION_JSON).check(&req));
+        assert!(!Accept(mime::IMAGE_PNG).check(&req));
+    }
+
+    #[test]
+    fn header_and_query() {
+        let mut req = request("/foo?page=2&debug");
+        req.headers_mut().set_raw("X-Api-Key", "secret");
+        assert!(Header::present("X-Api-Key").check(&req));
+        assert!(Header::equals("x-api-key", "secret").check(&req));
+        assert!(!Header::equals("X-Api-Key", "wrong").check(&req));
+        assert!(!Header::present("X-Other").check(&req));
+
+        assert!(Query::present("debug").check(&req));
+        assert!(Query::equals("page", "2").check(&req));
+        assert!(!Query::equals("page", "3").check(&req));
+        assert!(!Query::present("page").check(&request("/foo")));
+    }
+}
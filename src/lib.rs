@@ -15,28 +15,97 @@ extern crate regex;
 extern crate url;
 extern crate vec_map;
 extern crate mime_guess;
+extern crate tokio_core;
+extern crate tokio_timer;
+extern crate unicode_normalization;
 
 use anymap::AnyMap;
+use anymap::any::{Any, IntoBox};
 use futures::IntoFuture;
 use hyper::{Request, Response};
+use param::{Params, PrefixParams};
+use router::MatchedRoute;
 use std::collections::HashMap;
 use std::error::Error;
+use std::net::SocketAddr;
 use std::ops::Deref;
 use std::sync::Arc;
 
-pub(crate) mod pattern; // TODO: move this to src/router/pattern.rs?
+pub mod guard;
+pub mod method_override;
+pub mod normalize;
+pub mod openapi;
 pub mod param;
+pub mod pattern;
+pub mod rate_limit;
+pub mod redirect;
 pub mod router;
 pub mod server;
 pub mod serve_static;
 pub(crate) mod util;
+pub mod version;
 
 pub struct Ctx<P = HashMap<String, String>> {
     pub params: P,
-    pub data: Arc<AnyMap>,
+    /// The route that matched the request.
+    pub route: Arc<MatchedRoute>,
+    /// Parameters captured by mount prefixes, which are not passed to `params`.
+    pub prefix_params: PrefixParams,
+    /// All parameters as they appear in the path, before percent-decoding, prefix ones first.
+    pub raw_params: Params,
+    pub data: Data,
     pub request: Request,
 }
 
+/// Typed data visible to a handler.
+#[derive(Clone)]
+pub struct Data {
+    /// Route scope first, followed by the scopes of the routers it was mounted through.
+    scopes: Arc<Vec<Arc<AnyMap>>>,
+    global: Arc<AnyMap>,
+    remote_addr: Option<SocketAddr>,
+}
+
+impl Data {
+    pub(crate) fn new(
+        scopes: Arc<Vec<Arc<AnyMap>>>,
+        global: Arc<AnyMap>,
+        remote_addr: Option<SocketAddr>,
+    ) -> Self {
+        Data {
+            scopes,
+            global,
+            remote_addr,
+        }
+    }
+
+    /// Looks up a value from the innermost scope outward, ending with the data given to the
+    /// server.
+    pub fn get<T: IntoBox<Any>>(&self) -> Option<&T> {
+        self.scopes
+            .iter()
+            .filter_map(|scope| scope.get::<T>())
+            .next()
+            .or_else(|| self.global.get::<T>())
+    }
+
+    pub fn global(&self) -> &Arc<AnyMap> {
+        &self.global
+    }
+
+    /// The address of the client, as given to the `RouteHandler` by the server.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+}
+
+impl<P> Ctx<P> {
+    /// The address of the client. Use this rather than the deprecated `Request::remote_addr`.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.data.remote_addr()
+    }
+}
+
 impl<P> Deref for Ctx<P> {
     type Target = Request;
 
@@ -0,0 +1,181 @@
+//! Lets HTML forms, which can only submit GET and POST, reach routes of other methods.
+
+use futures::{future, Future, Stream};
+use hyper::{self, Method, Request};
+use hyper::header::{ContentLength, ContentType};
+use hyper::mime;
+use url::form_urlencoded;
+
+pub const HEADER: &'static str = "X-HTTP-Method-Override";
+pub const FORM_FIELD: &'static str = "_method";
+
+/// Rewrites the method of POST requests before routing, as requested by the
+/// `X-HTTP-Method-Override` header or the `_method` field of a URL-encoded form.
+#[derive(Debug, Clone)]
+pub struct MethodOverride {
+    allowed: Vec<Method>,
+    header: bool,
+    form_field: bool,
+    max_form_size: u64,
+}
+
+impl MethodOverride {
+    /// Allows overriding with PUT, PATCH and DELETE, from both the header and the form field.
+    pub fn new() -> Self {
+        MethodOverride {
+            allowed: vec![Method::Put, Method::Patch, Method::Delete],
+            header: true,
+            form_field: true,
+            max_form_size: 64 * 1024,
+        }
+    }
+
+    /// Sets the methods a POST request may be turned into. Other values are ignored.
+    pub fn allow(mut self, methods: &[Method]) -> Self {
+        self.allowed = methods.to_vec();
+        self
+    }
+
+    pub fn header(mut self, yes: bool) -> Self {
+        self.header = yes;
+        self
+    }
+
+    /// Reading the form field buffers the whole request body.
+    pub fn form_field(mut self, yes: bool) -> Self {
+        self.form_field = yes;
+        self
+    }
+
+    /// The largest body buffered to read the form field, 64 KiB by default. The field of larger
+    /// forms, and of forms without `Content-Length`, is ignored.
+    pub fn max_form_size(mut self, bytes: u64) -> Self {
+        self.max_form_size = bytes;
+        self
+    }
+
+    fn allowed(&self, value: &[u8]) -> Option<Method> {
+        let method = String::from_utf8_lossy(value).to_uppercase().parse().ok()?;
+        if self.allowed.contains(&method) {
+            Some(method)
+        } else {
+            None
+        }
+    }
+
+    /// Returns `req` with its method overridden, if requested and allowed.
+    pub fn apply(&self, mut req: Request) -> Box<Future<Item = Request, Error = hyper::Error>> {
+        if *req.method() != Method::Post {
+            return Box::new(future::ok(req));
+        }
+
+        if self.header {
+            let method = req.headers()
+                .get_raw(HEADER)
+                .and_then(|raw| raw.one())
+                .and_then(|v| self.allowed(v));
+            if let Some(method) = method {
+                req.set_method(method);
+                return Box::new(future::ok(req));
+            }
+        }
+
+        let is_form = req.headers()
+            .get::<ContentType>()
+            .map_or(false, |ct| {
+                ct.type_() == mime::APPLICATION && ct.subtype() == mime::WWW_FORM_URLENCODED
+            });
+        // the length is enforced by hyper, so it bounds what is buffered
+        let small = req.headers()
+            .get::<ContentLength>()
+            .map_or(false, |len| len.0 <= self.max_form_size);
+        if !self.form_field || !is_form || !small {
+            return Box::new(future::ok(req));
+        }
+
+        let body = req.body_mut().take().unwrap_or_default();
+        let this = self.clone();
+        Box::new(body.concat2().map(move |chunk| {
+            let method = form_urlencoded::parse(&chunk)
+                .find(|&(ref k, _)| k == FORM_FIELD)
+                .and_then(|(_, v)| this.allowed(v.as_bytes()));
+            if let Some(method) = method {
+                req.set_method(method);
+            }
+            req.set_body(chunk);
+            req
+        }))
+    }
+}
+
+impl Default for MethodOverride {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn post(body: Option<&'static str>) -> Request {
+        let mut req = Request::new(Method::Post, "/items/1".parse().unwrap());
+        if let Some(body) = body {
+            req.headers_mut()
+                .set(ContentType(mime::APPLICATION_WWW_FORM_URLENCODED));
+            req.headers_mut().set(ContentLength(body.len() as u64));
+            req.set_body(body);
+        }
+        req
+    }
+
+    #[test]
+    fn header() {
+        let mo = MethodOverride::new();
+        let mut req = post(None);
+        req.headers_mut().set_raw(HEADER, "delete");
+        assert_eq!(*mo.apply(req).wait().unwrap().method(), Method::Delete);
+
+        let mut req = post(None);
+        req.headers_mut().set_raw(HEADER, "CONNECT");
+        assert_eq!(*mo.apply(req).wait().unwrap().method(), Method::Post);
+
+        let mut req = Request::new(Method::Get, "/".parse().unwrap());
+        req.headers_mut().set_raw(HEADER, "DELETE");
+        assert_eq!(*mo.apply(req).wait().unwrap().method(), Method::Get);
+
+        let mut req = post(None);
+        req.headers_mut().set_raw(HEADER, "DELETE");
+        let mo = MethodOverride::new().header(false);
+        assert_eq!(*mo.apply(req).wait().unwrap().method(), Method::Post);
+    }
+
+    #[test]
+    fn form_field() {
+        let mo = MethodOverride::new();
+        let req = mo.apply(post(Some("name=foo&_method=PUT"))).wait().unwrap();
+        assert_eq!(*req.method(), Method::Put);
+        let body = req.body().concat2().wait().unwrap();
+        assert_eq!(&body[..], b"name=foo&_method=PUT");
+
+        let mo = MethodOverride::new().allow(&[Method::Delete]);
+        let req = mo.apply(post(Some("_method=PUT"))).wait().unwrap();
+        assert_eq!(*req.method(), Method::Post);
+    }
+
+    #[test]
+    fn form_size() {
+        let mo = MethodOverride::new().max_form_size(11);
+        let req = mo.apply(post(Some("_method=PUT"))).wait().unwrap();
+        assert_eq!(*req.method(), Method::Put);
+
+        let req = mo.apply(post(Some("a&_method=PUT"))).wait().unwrap();
+        assert_eq!(*req.method(), Method::Post);
+        let body = req.body().concat2().wait().unwrap();
+        assert_eq!(&body[..], b"a&_method=PUT");
+
+        let mut req = post(Some("_method=PUT"));
+        req.headers_mut().remove::<ContentLength>();
+        assert_eq!(*mo.apply(req).wait().unwrap().method(), Method::Post);
+    }
+}
@@ -0,0 +1,85 @@
+//! Puts request paths in canonical form before routing, so that `/admin` can't be reached as
+//! `/public/../admin` or `//admin` without going through the routes of `/admin`.
+
+/// What the router does with a request whose path isn't canonical.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    /// Routes the request as if it had been made to the canonical path, which handlers see.
+    Rewrite,
+    /// Answers with 308 Permanent Redirect to the canonical path.
+    Redirect,
+}
+
+/// Collapses duplicate slashes and resolves `.` and `..` segments, including percent-encoded
+/// ones, as in RFC 3986. `..` never climbs above the root. Returns `None` if `path` is already
+/// canonical.
+///
+/// Note that this removes the empty segments that `{param?}` can match in the middle of a path.
+pub fn canonical_path(path: &str) -> Option<String> {
+    let mut out: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    let mut segments = path[1..].split('/').peekable();
+    while let Some(seg) = segments.next() {
+        let last = segments.peek().is_none();
+        match dots(seg) {
+            _ if seg.is_empty() => (),
+            Some(1) => (),
+            Some(_) => {
+                out.pop();
+            }
+            None => out.push(seg),
+        }
+        trailing_slash = last && (seg.is_empty() || dots(seg).is_some());
+    }
+
+    let mut canonical = String::with_capacity(path.len());
+    for seg in &out {
+        canonical.push('/');
+        canonical.push_str(seg);
+    }
+    if trailing_slash || out.is_empty() {
+        canonical.push('/');
+    }
+    if canonical == path {
+        None
+    } else {
+        Some(canonical)
+    }
+}
+
+/// The number of dots if `seg` is `.` or `..`, possibly percent-encoded.
+fn dots(seg: &str) -> Option<usize> {
+    if seg.len() > 6 {
+        return None;
+    }
+    match &*seg.to_ascii_lowercase().replace("%2e", ".") {
+        "." => Some(1),
+        ".." => Some(2),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn canonical() {
+        assert_eq!(canonical_path("/"), None);
+        assert_eq!(canonical_path("/a/b"), None);
+        assert_eq!(canonical_path("/a/b/"), None);
+        assert_eq!(canonical_path("/a/.b/c..d/..."), None);
+        assert_eq!(canonical_path("//").unwrap(), "/");
+        assert_eq!(canonical_path("/a//b").unwrap(), "/a/b");
+        assert_eq!(canonical_path("/a/b//").unwrap(), "/a/b/");
+        assert_eq!(canonical_path("/a/./b").unwrap(), "/a/b");
+        assert_eq!(canonical_path("/a/b/.").unwrap(), "/a/b/");
+        assert_eq!(canonical_path("/a/../b").unwrap(), "/b");
+        assert_eq!(canonical_path("/a/b/..").unwrap(), "/a/");
+        assert_eq!(canonical_path("/../../a").unwrap(), "/a");
+        assert_eq!(canonical_path("/..").unwrap(), "/");
+        assert_eq!(canonical_path("/public/%2E%2e/admin").unwrap(), "/admin");
+        assert_eq!(canonical_path("/public/.%2e/admin").unwrap(), "/admin");
+        assert_eq!(canonical_path("/a/%2e/b").unwrap(), "/a/b");
+    }
+}
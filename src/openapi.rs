@@ -0,0 +1,569 @@
+//! OpenAPI 3 documents generated from the route table. See `Router::openapi`.
+
+use {Ctx, Handler};
+use hyper::{self, Response};
+use hyper::header::ContentType;
+use hyper::mime::{self, Mime};
+use pattern::{Constraint, Part, Pattern, Segment, Terminator};
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// A JSON value, used for schemas and for the document itself.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    String(String),
+    Array(Vec<Value>),
+    /// Keys are kept in insertion order.
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn object<K: Into<String>, V: Into<Value>, I: IntoIterator<Item = (K, V)>>(it: I) -> Self {
+        Value::Object(it.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
+    }
+
+    /// `{"type": ty}`
+    pub fn schema(ty: &str) -> Self {
+        Value::object(vec![("type", ty)])
+    }
+
+    fn insert<K: Into<String>, V: Into<Value>>(&mut self, key: K, value: V) {
+        if let Value::Object(ref mut fields) = *self {
+            fields.push((key.into(), value.into()));
+        } else {
+            panic!("not an object");
+        }
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        if let Value::Object(ref mut fields) = *self {
+            fields.iter_mut().find(|f| f.0 == key).map(|f| &mut f.1)
+        } else {
+            None
+        }
+    }
+
+    fn is_scalar(&self) -> bool {
+        match *self {
+            Value::Array(ref v) => v.is_empty(),
+            Value::Object(ref v) => v.is_empty(),
+            _ => true,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+
+    fn write_json(&self, out: &mut String) {
+        match *self {
+            Value::Null => out.push_str("null"),
+            Value::Bool(b) => out.push_str(if b { "true" } else { "false" }),
+            Value::Integer(n) => write!(out, "{}", n).unwrap(),
+            Value::String(ref s) => write_json_string(s, out),
+            Value::Array(ref items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    item.write_json(out);
+                }
+                out.push(']');
+            }
+            Value::Object(ref fields) => {
+                out.push('{');
+                for (i, &(ref k, ref v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_json_string(k, out);
+                    out.push(':');
+                    v.write_json(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+
+    /// Block-style YAML. Strings are always double-quoted.
+    pub fn to_yaml(&self) -> String {
+        let mut out = String::new();
+        if self.is_scalar() {
+            self.write_json(&mut out);
+            out.push('\n');
+        } else {
+            self.write_yaml(0, &mut out);
+        }
+        out
+    }
+
+    fn write_yaml(&self, indent: usize, out: &mut String) {
+        match *self {
+            Value::Array(ref items) => for item in items {
+                push_indent(indent, out);
+                out.push_str("- ");
+                if item.is_scalar() {
+                    item.write_json(out);
+                    out.push('\n');
+                } else {
+                    // put the first line of the nested block right after `- `
+                    let mut nested = String::new();
+                    item.write_yaml(indent + 2, &mut nested);
+                    out.push_str(&nested[indent + 2..]);
+                }
+            },
+            Value::Object(ref fields) => for &(ref k, ref v) in fields {
+                push_indent(indent, out);
+                write_json_string(k, out);
+                out.push(':');
+                if v.is_scalar() {
+                    out.push(' ');
+                    v.write_json(out);
+                    out.push('\n');
+                } else {
+                    out.push('\n');
+                    v.write_yaml(indent + 2, out);
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn push_indent(indent: usize, out: &mut String) {
+    for _ in 0..indent {
+        out.push(' ');
+    }
+}
+
+fn write_json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(s: &'a str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Integer(n)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(v: Vec<Value>) -> Self {
+        Value::Array(v)
+    }
+}
+
+/// Documentation of a route, attached with `Route::doc`.
+#[derive(Debug, Clone, Default)]
+pub struct Operation {
+    summary: Option<String>,
+    description: Option<String>,
+    operation_id: Option<String>,
+    tags: Vec<String>,
+    parameters: Vec<(String, Option<String>, Value)>,
+    request_body: Option<(String, Value)>,
+    responses: Vec<(String, String, Option<Value>)>,
+}
+
+impl Operation {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn summary<S: Into<String>>(mut self, s: S) -> Self {
+        self.summary = Some(s.into());
+        self
+    }
+
+    pub fn description<S: Into<String>>(mut self, s: S) -> Self {
+        self.description = Some(s.into());
+        self
+    }
+
+    pub fn operation_id<S: Into<String>>(mut self, s: S) -> Self {
+        self.operation_id = Some(s.into());
+        self
+    }
+
+    pub fn tag<S: Into<String>>(mut self, s: S) -> Self {
+        self.tags.push(s.into());
+        self
+    }
+
+    /// Documents the path parameter `name`, whose schema is `{"type": "string"}` by default.
+    pub fn parameter<N: Into<String>>(
+        mut self,
+        name: N,
+        description: Option<&str>,
+        schema: Value,
+    ) -> Self {
+        self.parameters
+            .push((name.into(), description.map(|s| s.to_string()), schema));
+        self
+    }
+
+    pub fn request_body<M: Into<String>>(mut self, media_type: M, schema: Value) -> Self {
+        self.request_body = Some((media_type.into(), schema));
+        self
+    }
+
+    /// `status` is a status code like `"200"`, or `"default"`.
+    pub fn response<S: Into<String>, D: Into<String>>(
+        mut self,
+        status: S,
+        description: D,
+        json_schema: Option<Value>,
+    ) -> Self {
+        self.responses
+            .push((status.into(), description.into(), json_schema));
+        self
+    }
+
+    fn to_value(&self, params: &[(String, Value, Option<&'static str>)]) -> Value {
+        let mut op = Value::object(Vec::<(String, Value)>::new());
+        if let Some(ref s) = self.summary {
+            op.insert("summary", s.clone());
+        }
+        if let Some(ref s) = self.description {
+            op.insert("description", s.clone());
+        }
+        if let Some(ref s) = self.operation_id {
+            op.insert("operationId", s.clone());
+        }
+        if !self.tags.is_empty() {
+            op.insert(
+                "tags",
+                self.tags
+                    .iter()
+                    .map(|t| Value::from(t.clone()))
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        if !params.is_empty() {
+            let params = params
+                .iter()
+                .map(|&(ref name, ref default_schema, note)| {
+                    let doc = self.parameters.iter().find(|p| p.0 == *name);
+                    let mut p = Value::object(vec![
+                        ("name", Value::from(name.clone())),
+                        ("in", "path".into()),
+                        ("required", true.into()),
+                    ]);
+                    let description = match (doc.and_then(|d| d.1.as_ref()), note) {
+                        (Some(d), Some(note)) => Some(format!("{} {}", d, note)),
+                        (Some(d), None) => Some(d.clone()),
+                        (None, note) => note.map(|s| s.to_string()),
+                    };
+                    if let Some(d) = description {
+                        p.insert("description", d);
+                    }
+                    p.insert(
+                        "schema",
+                        doc.map_or_else(|| default_schema.clone(), |d| d.2.clone()),
+                    );
+                    p
+                })
+                .collect::<Vec<_>>();
+            op.insert("parameters", params);
+        }
+
+        if let Some((ref media_type, ref schema)) = self.request_body {
+            op.insert(
+                "requestBody",
+                Value::object(vec![
+                    (
+                        "content",
+                        Value::object(vec![
+                            (
+                                media_type.clone(),
+                                Value::object(vec![("schema", schema.clone())]),
+                            ),
+                        ]),
+                    ),
+                ]),
+            );
+        }
+
+        let responses = if self.responses.is_empty() {
+            Value::object(vec![
+                ("default", Value::object(vec![("description", "")])),
+            ])
+        } else {
+            Value::Object(
+                self.responses
+                    .iter()
+                    .map(|&(ref status, ref description, ref schema)| {
+                        let mut r = Value::object(vec![("description", description.clone())]);
+                        if let Some(ref schema) = *schema {
+                            r.insert(
+                                "content",
+                                Value::object(vec![
+                                    (
+                                        "application/json",
+                                        Value::object(vec![("schema", schema.clone())]),
+                                    ),
+                                ]),
+                            );
+                        }
+                        (status.clone(), r)
+                    })
+                    .collect(),
+            )
+        };
+        op.insert("responses", responses);
+        op
+    }
+}
+
+/// An OpenAPI 3 document.
+#[derive(Debug, Clone)]
+pub struct Document {
+    info: Value,
+    servers: Vec<Value>,
+    /// Path templates in route precedence order.
+    paths: Value,
+}
+
+impl Document {
+    pub fn new<T: Into<String>, V: Into<String>>(title: T, version: V) -> Self {
+        Document {
+            info: Value::object(vec![("title", title.into()), ("version", version.into())]),
+            servers: Vec::new(),
+            paths: Value::Object(Vec::new()),
+        }
+    }
+
+    pub fn description<S: Into<String>>(mut self, s: S) -> Self {
+        self.info.insert("description", s.into());
+        self
+    }
+
+    /// Adds a server URL. Use the mount prefix when documenting a mounted router.
+    pub fn server<S: Into<String>>(mut self, url: S) -> Self {
+        self.servers.push(Value::object(vec![("url", url.into())]));
+        self
+    }
+
+    /// Documents `op` under every path `pattern` can take; OpenAPI has no optional segments.
+    pub(crate) fn add_operation(&mut self, method: &str, pattern: &Pattern, op: &Operation) {
+        for alt in pattern.alternatives() {
+            let (path, params) = path_template(&alt);
+            if self.paths.get_mut(&path).is_none() {
+                self.paths.insert(path.clone(), Value::Object(Vec::new()));
+            }
+            let item = self.paths.get_mut(&path).unwrap();
+            if item.get_mut(method).is_none() {
+                item.insert(method, op.to_value(&params));
+            }
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut doc = Value::object(vec![
+            ("openapi", Value::from("3.0.0")),
+            ("info", self.info.clone()),
+        ]);
+        if !self.servers.is_empty() {
+            doc.insert("servers", self.servers.clone());
+        }
+        doc.insert("paths", self.paths.clone());
+        doc
+    }
+
+    pub fn to_json(&self) -> String {
+        self.to_value().to_json()
+    }
+
+    pub fn to_yaml(&self) -> String {
+        self.to_value().to_yaml()
+    }
+
+    /// A handler serving this document as JSON.
+    pub fn json_handler(&self) -> Spec {
+        Spec {
+            body: Arc::new(self.to_json()),
+            mime: mime::APPLICATION_JSON,
+        }
+    }
+
+    /// A handler serving this document as YAML.
+    pub fn yaml_handler(&self) -> Spec {
+        Spec {
+            body: Arc::new(self.to_yaml()),
+            mime: "application/yaml".parse().unwrap(),
+        }
+    }
+}
+
+/// The default schema of a path parameter.
+fn parameter_schema(allow_empty: bool, constraint: Option<&Constraint>) -> Value {
+    match constraint {
+        Some(&Constraint::Integer(ref ty)) if ty.starts_with('u') => Value::object(vec![
+            ("type", Value::from("integer")),
+            ("minimum", 0.into()),
+        ]),
+        Some(&Constraint::Integer(..)) => Value::schema("integer"),
+        Some(&Constraint::Uuid) => Value::object(vec![("type", "string"), ("format", "uuid")]),
+        Some(&Constraint::Regex(ref re)) => Value::object(vec![
+            ("type", Value::from("string")),
+            ("pattern", format!("^(?:{})$", re).into()),
+        ]),
+        None if allow_empty => Value::schema("string"),
+        None => Value::object(vec![
+            ("type", Value::from("string")),
+            ("minLength", 1.into()),
+        ]),
+    }
+}
+
+/// Renders `pattern` as an OpenAPI path template, returning its parameters with their default
+/// schemas and notes.
+fn path_template(pattern: &Pattern) -> (String, Vec<(String, Value, Option<&'static str>)>) {
+    let mut path = String::new();
+    let mut wildcards = Vec::new();
+    for seg in pattern.segments() {
+        path.push('/');
+        match *seg {
+            Segment::Fixed(ref s) => path.push_str(s),
+            Segment::Parameter(ref name, ..) => path.push_str(&format!("{{{}}}", name)),
+            Segment::Mixed(ref parts) => for part in parts {
+                match *part {
+                    Part::Literal(ref s) => path.push_str(s),
+                    Part::Parameter(ref name, ..) => path.push_str(&format!("{{{}}}", name)),
+                }
+            },
+            Segment::Wildcard(ref name) => {
+                path.push_str(&format!("{{{}}}", name));
+                wildcards.push(name);
+            }
+            Segment::Optional(_) => unreachable!("groups are expanded before rendering"),
+        }
+    }
+    let mut params = pattern
+        .parameters()
+        .into_iter()
+        .map(|(name, allow_empty, c)| {
+            let note = if wildcards.iter().any(|w| *w == name) {
+                Some("Matches one or more segments, which may contain `/`.")
+            } else {
+                None
+            };
+            (name.to_string(), parameter_schema(allow_empty, c), note)
+        })
+        .collect::<Vec<_>>();
+    match pattern.terminator() {
+        Some(&Terminator::Tail(ref name)) => {
+            path.push_str(&format!("/{{{}}}", name));
+            params.push((
+                name.clone(),
+                Value::schema("string"),
+                Some("Matches the rest of the path, which may contain `/`."),
+            ));
+        }
+        Some(&Terminator::OptionalSlash) | None => (),
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    (path, params)
+}
+
+/// Serves a `Document`.
+#[derive(Debug, Clone)]
+pub struct Spec {
+    body: Arc<String>,
+    mime: Mime,
+}
+
+impl Handler<()> for Spec {
+    type Result = Result<Response, hyper::Error>;
+    type Error = hyper::Error;
+
+    fn call(&self, _: Ctx<()>) -> Self::Result {
+        Ok(Response::new()
+            .with_header(ContentType(self.mime.clone()))
+            .with_body(self.body.as_str().to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn json_and_yaml() {
+        let v = Value::object(vec![
+            ("a", Value::from("x\"y")),
+            ("b", vec![Value::from(1), Value::object(vec![("c", true), ("d", false)])].into()),
+            ("e", Value::Object(vec![])),
+        ]);
+        assert_eq!(
+            v.to_json(),
+            r#"{"a":"x\"y","b":[1,{"c":true,"d":false}],"e":{}}"#
+        );
+        assert_eq!(
+            v.to_yaml(),
+            r#""a": "x\"y"
+"b":
+  - 1
+  - "c": true
+    "d": false
+"e": {}
+"#
+        );
+    }
+
+    #[test]
+    fn template() {
+        let t = |s: &str| path_template(&s.parse().unwrap()).0;
+        assert_eq!(t("/"), "/");
+        assert_eq!(t("/users/{id}/"), "/users/{id}/");
+        assert_eq!(t("/users/{id}/?"), "/users/{id}");
+        assert_eq!(t("/static/:path"), "/static/{path}");
+        assert_eq!(t("/:path"), "/{path}");
+        assert_eq!(t("/files/{name}.{ext:[a-z]+}"), "/files/{name}.{ext}");
+
+        let (_, params) = path_template(&"/{id:u64}/{slug:[a-z]+}".parse().unwrap());
+        assert_eq!(params[0].1.to_json(), r#"{"type":"integer","minimum":0}"#);
+        assert_eq!(
+            params[1].1.to_json(),
+            r#"{"type":"string","pattern":"^(?:[a-z]+)$"}"#
+        );
+    }
+}
@@ -2,14 +2,74 @@ use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::hash::BuildHasher;
+use std::ops::Deref;
 use std::str::FromStr;
 
-// TODO: FromParam / FromParamIter trait
-
 pub trait FromParameters: Sized {
     fn from_parameters<'a, I: IntoIterator<Item = (&'a str, &'a str)>>(
         params: I,
     ) -> Result<Self, Cow<'static, str>>;
+
+    /// Like `from_parameters`, but parameters of optional groups absent from the path come as
+    /// `None`. They're left out unless overridden.
+    fn from_optional_parameters<'a, I: IntoIterator<Item = (&'a str, Option<&'a str>)>>(
+        params: I,
+    ) -> Result<Self, Cow<'static, str>> {
+        Self::from_parameters(params.into_iter().filter_map(|(n, v)| v.map(|v| (n, v))))
+    }
+
+    /// The number of parameters taken, if fixed, checked against the pattern when a route is
+    /// registered.
+    fn arity() -> Option<usize> {
+        None
+    }
+}
+
+/// The value of a parameter, as an element of a tuple of parameters. Implemented for every
+/// `FromStr` type, and for `Optional`.
+pub trait FromParameter: Sized {
+    /// `value` is `None` if the parameter is in an optional group absent from the path.
+    fn from_parameter(value: Option<&str>) -> Result<Self, Cow<'static, str>>;
+}
+
+impl<T> FromParameter for T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    fn from_parameter(value: Option<&str>) -> Result<Self, Cow<'static, str>> {
+        value
+            .ok_or(Cow::from("missing parameter"))?
+            .parse()
+            .map_err(|e: T::Err| Cow::from(e.to_string()))
+    }
+}
+
+/// A parameter of an optional group, `None` if the group is absent from the path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Optional<T>(pub Option<T>);
+
+impl<T> Optional<T> {
+    pub fn into_inner(self) -> Option<T> {
+        self.0
+    }
+}
+
+impl<T> Deref for Optional<T> {
+    type Target = Option<T>;
+
+    fn deref(&self) -> &Option<T> {
+        &self.0
+    }
+}
+
+impl<T: FromParameter> FromParameter for Optional<T> {
+    fn from_parameter(value: Option<&str>) -> Result<Self, Cow<'static, str>> {
+        match value {
+            Some(_) => T::from_parameter(value).map(|v| Optional(Some(v))),
+            None => Ok(Optional(None)),
+        }
+    }
 }
 
 impl FromParameters for () {
@@ -22,40 +82,40 @@ impl FromParameters for () {
             Ok(())
         }
     }
-}
 
-impl<A> FromParameters for (A,)
-where
-    A: FromStr,
-    A::Err: Display,
-{
-    fn from_parameters<'a, I: IntoIterator<Item = (&'a str, &'a str)>>(
-        params: I,
+    fn from_optional_parameters<'a, I: IntoIterator<Item = (&'a str, Option<&'a str>)>>(
+        it: I,
     ) -> Result<Self, Cow<'static, str>> {
-        let mut params = params.into_iter();
-        let (_, value) = params.next().ok_or_else(|| Cow::from("missing parameter"))?;
-        let t = (value.parse().map_err(|e: A::Err| Cow::from(e.to_string()))?,);
-        if params.next().is_some() {
+        if it.into_iter().next().is_some() {
             Err(Cow::from("excess parameters"))
         } else {
-            Ok(t)
+            Ok(())
         }
     }
+
+    fn arity() -> Option<usize> {
+        Some(0)
+    }
 }
 
 macro_rules! tuple_from_parameters {
     ($($tv:ident),+) => {
-        impl<$($tv: FromStr),*> FromParameters for ($($tv),*)
-        where
-            $($tv::Err: Display),+
-        {
+        impl<$($tv: FromParameter),+> FromParameters for ($($tv,)+) {
             fn from_parameters<'a, It: IntoIterator<Item = (&'a str, &'a str)>>(
                 params: It,
             ) -> Result<Self, Cow<'static, str>> {
+                Self::from_optional_parameters(params.into_iter().map(|(n, v)| (n, Some(v))))
+            }
+
+            fn from_optional_parameters<'a, It>(params: It) -> Result<Self, Cow<'static, str>>
+            where
+                It: IntoIterator<Item = (&'a str, Option<&'a str>)>,
+            {
                 let mut params = params.into_iter();
                 let t = (
-                    $(params.next().ok_or(Cow::from("missing parameter"))?.1
-                        .parse().map_err(|e: $tv::Err| Cow::from(e.to_string()))?),*
+                    $($tv::from_parameter(
+                        params.next().ok_or(Cow::from("missing parameter"))?.1,
+                    )?,)+
                 );
                 if params.next().is_some() {
                     Err(Cow::from("excess parameters"))
@@ -63,10 +123,15 @@ macro_rules! tuple_from_parameters {
                     Ok(t)
                 }
             }
+
+            fn arity() -> Option<usize> {
+                Some([$(stringify!($tv)),+].len())
+            }
         }
     };
 }
 
+tuple_from_parameters!(A);
 tuple_from_parameters!(A, B);
 tuple_from_parameters!(A, B, C);
 tuple_from_parameters!(A, B, C, D);
@@ -77,6 +142,130 @@ tuple_from_parameters!(A, B, C, D, E, F, G, H);
 tuple_from_parameters!(A, B, C, D, E, F, G, H, I);
 tuple_from_parameters!(A, B, C, D, E, F, G, H, I, J);
 
+/// Named parameters in pattern order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Params(Vec<(String, String)>);
+
+/// Parameters captured by the prefixes of `Router::mount`, outermost first.
+pub type PrefixParams = Params;
+
+impl Params {
+    pub(crate) fn new(params: Vec<(String, String)>) -> Self {
+        Params(params)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|&&(ref n, _)| n == name)
+            .map(|&(_, ref v)| v.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item = (&'a str, &'a str)> + 'a> {
+        Box::new(self.0.iter().map(|&(ref n, ref v)| (n.as_str(), v.as_str())))
+    }
+
+    /// Converts the parameters as if they were captured by the route itself.
+    pub fn parse<P: FromParameters>(&self) -> Result<P, Cow<'static, str>> {
+        P::from_parameters(self.iter())
+    }
+}
+
+/// What to do with parameters that don't decode to valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidUtf8 {
+    /// Respond with 400 Bad Request.
+    Reject,
+    /// Replace invalid sequences with U+FFFD.
+    Replace,
+}
+
+/// What to do with `%2F` in parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodedSlash {
+    /// Leave it encoded, so that parameters never contain `/` except in `:tail`.
+    Keep,
+    Decode,
+    /// Respond with 400 Bad Request.
+    Reject,
+}
+
+/// How path parameters are percent-decoded. The raw captures are kept in `Ctx::raw_params`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decoding {
+    pub invalid_utf8: InvalidUtf8,
+    pub encoded_slash: EncodedSlash,
+}
+
+impl Default for Decoding {
+    fn default() -> Self {
+        Decoding {
+            invalid_utf8: InvalidUtf8::Reject,
+            encoded_slash: EncodedSlash::Keep,
+        }
+    }
+}
+
+fn hex(b: u8) -> Option<u8> {
+    match b {
+        b'0'...b'9' => Some(b - b'0'),
+        b'a'...b'f' => Some(b - b'a' + 10),
+        b'A'...b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+impl Decoding {
+    /// Decodes `%XX` sequences in `s`. Malformed sequences are left as they are.
+    pub fn decode<'a>(&self, s: &'a str) -> Result<Cow<'a, str>, Cow<'static, str>> {
+        if !s.contains('%') {
+            return Ok(Cow::from(s));
+        }
+
+        let bytes = s.as_bytes();
+        let mut out = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let decoded = if bytes[i] == b'%' && i + 2 < bytes.len() {
+                hex(bytes[i + 1]).and_then(|h| hex(bytes[i + 2]).map(|l| h << 4 | l))
+            } else {
+                None
+            };
+            match decoded {
+                Some(b'/') if self.encoded_slash == EncodedSlash::Keep => {
+                    out.extend_from_slice(&bytes[i..i + 3]);
+                    i += 3;
+                }
+                Some(b'/') if self.encoded_slash == EncodedSlash::Reject => {
+                    return Err(Cow::from("encoded slash in path parameter"));
+                }
+                Some(b) => {
+                    out.push(b);
+                    i += 3;
+                }
+                None => {
+                    out.push(bytes[i]);
+                    i += 1;
+                }
+            }
+        }
+
+        match String::from_utf8(out) {
+            Ok(s) => Ok(Cow::from(s)),
+            Err(e) => match self.invalid_utf8 {
+                InvalidUtf8::Reject => Err(Cow::from("path parameter is not valid UTF-8")),
+                InvalidUtf8::Replace => {
+                    Ok(Cow::from(String::from_utf8_lossy(e.as_bytes()).into_owned()))
+                }
+            },
+        }
+    }
+}
+
 impl<T, S: BuildHasher + Default> FromParameters for HashMap<String, T, S>
 where
     T: FromStr,
@@ -118,6 +307,62 @@ mod test {
         assert!(<(i32,)>::from_parameters(vec![("foo", "1234"), ("bar", "baz")]).is_err());
     }
 
+    #[test]
+    fn from_str() {
+        use std::path::PathBuf;
+
+        #[derive(Debug, PartialEq)]
+        struct Slug(String);
+
+        impl FromStr for Slug {
+            type Err = &'static str;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                if s.chars().all(|c| c.is_ascii_lowercase() || c == '-') {
+                    Ok(Slug(s.to_string()))
+                } else {
+                    Err("invalid slug")
+                }
+            }
+        }
+
+        assert_eq!(
+            <(Slug, PathBuf)>::from_parameters(vec![("slug", "a-b"), ("path", "c/d")]).unwrap(),
+            (Slug("a-b".to_string()), PathBuf::from("c/d"))
+        );
+        assert_eq!(
+            <(Slug,)>::from_parameters(vec![("slug", "A")]),
+            Err(Cow::from("invalid slug"))
+        );
+        assert_eq!(
+            <(Optional<Slug>,)>::from_optional_parameters(vec![("slug", Some("a"))]).unwrap(),
+            (Optional(Some(Slug("a".to_string()))),)
+        );
+    }
+
+    #[test]
+    fn optional() {
+        assert_eq!(
+            <(u32, Optional<u8>, Optional<u8>)>::from_optional_parameters(vec![
+                ("year", Some("2018")),
+                ("month", Some("5")),
+                ("day", None),
+            ]).unwrap(),
+            (2018, Optional(Some(5)), Optional(None))
+        );
+        assert_eq!(
+            <(u32, u8)>::from_optional_parameters(vec![("year", Some("2018")), ("month", None)]),
+            Err(Cow::from("missing parameter"))
+        );
+        assert!(<(Optional<u8>,)>::from_optional_parameters(vec![("n", Some("x"))]).is_err());
+
+        let map = HashMap::<String, u8>::from_optional_parameters(vec![
+            ("month", Some("5")),
+            ("day", None),
+        ]).unwrap();
+        assert_eq!(map.len(), 1);
+    }
+
     #[test]
     fn map() {
         assert!(
@@ -132,4 +377,27 @@ mod test {
                 .len() == 2
         );
     }
+
+    #[test]
+    fn decoding() {
+        let d = Decoding::default();
+        assert_eq!(d.decode("J%C3%BCrgen").unwrap(), "Jürgen");
+        assert_eq!(d.decode("a%20b%2").unwrap(), "a b%2");
+        assert_eq!(d.decode("100%").unwrap(), "100%");
+        assert_eq!(d.decode("a%2Fb").unwrap(), "a%2Fb");
+        assert!(d.decode("%FF").is_err());
+
+        let d = Decoding {
+            invalid_utf8: InvalidUtf8::Replace,
+            encoded_slash: EncodedSlash::Decode,
+        };
+        assert_eq!(d.decode("a%2fb").unwrap(), "a/b");
+        assert_eq!(d.decode("%FFa").unwrap(), "\u{FFFD}a");
+
+        let d = Decoding {
+            encoded_slash: EncodedSlash::Reject,
+            ..d
+        };
+        assert!(d.decode("a%2Fb").is_err());
+    }
 }
@@ -1,22 +1,55 @@
-use itertools::Itertools;
-use param;
-use regex::{self, Regex, RegexSet};
+//! Path patterns, usable on their own to match any `/`-separated keys.
+//!
+//! # Grammar
+//!
+//! A pattern is a `/` followed by segments separated by `/`, and optionally a terminator:
+//!
+//! - `text` is a fixed segment. Characters outside `pchar` of RFC 3986 must be
+//!   percent-encoded, except non-ASCII ones. Paths are compared after percent-decoding.
+//! - `{name}` is a parameter capturing a non-empty segment, `{name?}` one that may be empty and
+//!   `{name:constraint}` one whose percent-decoded text must satisfy `constraint`: an integer
+//!   type such as `u32` or `i64`, `uuid`, or a regular expression.
+//! - `text{name}text` mixes literal text and parameters, which must not be adjacent.
+//! - `**` or `:name` before the last segment captures one or more segments, `/` included.
+//! - `[/segments]` is an optional group of whole segments, which may nest. Parameters of
+//!   absent groups are captured as `None`, and handlers take them as `param::Optional`.
+//! - A trailing `/?` makes the trailing slash optional, and a trailing `/:name` captures the
+//!   rest of the path, `/` included.
+//! - A leading `(?i)` makes the literal text match regardless of ASCII case.
+//!
+//! A path matching several patterns of a `PatternSet` goes to the most specific one, as given
+//! by `Ord for Pattern`: fixed segments before mixed ones, then parameters, then wildcards.
+//!
+//! `Display` renders a pattern in a form that parses back to an equal pattern, so patterns can
+//! be stored as strings.
+
+use fxhash::FxHashMap;
+use param::Decoding;
+use regex::Regex;
 use std::borrow::Cow;
+use std::cell::RefCell;
 use std::cmp::{Ord, Ordering};
-use std::collections::BTreeMap;
+use std::error::Error;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::btree_map;
 use std::fmt::{self, Display, Formatter};
 use std::iter::FromIterator;
 use std::str::FromStr;
-use vec_map::VecMap;
+use unicode_normalization::UnicodeNormalization;
 
+/// Returns `$or` for paths that don't start with `/`, such as the `*` of `OPTIONS *`.
 macro_rules! check_path {
-    ($path:expr) => {
-        debug_assert!($path.starts_with('/'), "paths must start with '/'");
+    ($path:expr, $or:expr) => {
+        if !$path.starts_with('/') {
+            return $or;
+        }
     };
 }
 
+/// Identifies a pattern of a `PatternSet`.
 pub type PatternToken = usize;
 
+/// Patterns, each with a token, to be compiled into a `CompiledPatternSet`.
 #[derive(Debug, Clone)]
 pub struct PatternSet {
     /// `Pattern`s in more-specific-first order.
@@ -51,6 +84,7 @@ impl PatternSet {
         }
     }
 
+    /// Adds `pat`, returning its token, or `None` if an equal pattern is already present.
     #[inline]
     pub fn insert(&mut self, pat: Pattern) -> Option<PatternToken> {
         if self.patterns.contains_key(&pat) {
@@ -62,6 +96,18 @@ impl PatternSet {
         Some(tok)
     }
 
+    /// The token of the pattern equal to `pat`.
+    #[inline]
+    pub fn get(&self, pat: &Pattern) -> Option<PatternToken> {
+        self.patterns.get(pat).cloned()
+    }
+
+    /// Iterates over `Pattern`s in more-specific-first order.
+    #[inline]
+    pub fn iter<'a>(&'a self) -> btree_map::Iter<'a, Pattern, PatternToken> {
+        self.patterns.iter()
+    }
+
     // pub fn is_match(&self, path: &str) -> bool {
     //     self.patterns.keys().any(|pat| pat.is_match(path))
     // }
@@ -77,17 +123,55 @@ impl PatternSet {
     // }
 
     pub fn compile(&self) -> CompiledPatternSet {
-        let mut map = VecMap::with_capacity(self.patterns.len());
-        let re_set = RegexSet::new(self.patterns.iter().enumerate().map(|(i, (pat, &tok))| {
-            map.insert(i, tok);
-            pat.to_re_string()
-        }));
+        self.compile_with(false)
+    }
+
+    /// Like `compile`, but fixed segments and paths are compared after NFC normalization if
+    /// `nfc`.
+    pub fn compile_with(&self, nfc: bool) -> CompiledPatternSet {
+        let mut root = Node::default();
+        let mut patterns = FxHashMap::default();
+        for (rank, (pat, &tok)) in self.patterns.iter().enumerate() {
+            let alternatives = pat.alternatives();
+            let cpat = pat.compile();
+            let params = if alternatives.len() > 1 {
+                cpat.params.clone()
+            } else {
+                vec![]
+            };
+            patterns.insert(tok, cpat);
+            for alt in alternatives {
+                let slots = if params.is_empty() {
+                    None
+                } else {
+                    let slots = alt.compile()
+                        .params
+                        .iter()
+                        .map(|n| params.iter().position(|p| p == n).expect("unknown parameter"))
+                        .collect();
+                    Some((params.len(), slots))
+                };
+                let end = End {
+                    rank,
+                    token: tok,
+                    terminator: alt.terminator.clone(),
+                    slots,
+                };
+                root.insert(&alt.segments, alt.case_insensitive, nfc, end);
+            }
+        }
         CompiledPatternSet {
-            re_set: re_set.expect("failed to compile regex"),
-            map,
+            root,
+            patterns,
+            nfc,
         }
     }
 
+    /// Prepends the segments of `prefix` to every pattern, keeping the tokens.
+    ///
+    /// # Panics
+    ///
+    /// If `prefix` has a terminator.
     pub fn prefix(&self, prefix: &Pattern) -> PatternSet {
         assert!(
             !prefix.terminated(),
@@ -99,6 +183,7 @@ impl PatternSet {
             let mut p = prefix.clone();
             p.segments.extend(pat.segments.iter().cloned());
             p.terminator = pat.terminator.clone();
+            p.case_insensitive = prefix.case_insensitive || pat.case_insensitive;
             patterns.insert(p, tok);
         }
         PatternSet {
@@ -106,50 +191,300 @@ impl PatternSet {
             next_tok: self.next_tok,
         }
     }
+}
 
-    pub fn merge(&mut self, other: &PatternSet) {
-        let new_next_tok = self.next_tok + other.next_tok;
-        let off = self.next_tok;
-        self.patterns.extend(
-            other
-                .patterns
-                .iter()
-                .map(|(pat, tok)| (pat.clone(), tok + off)),
-        );
-        self.next_tok = new_next_tok;
+/// A pattern matching a path, with the byte ranges of its parameters in the path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Match {
+    pub token: PatternToken,
+    /// In the order of `CompiledPattern::params`. `None` for parameters in optional groups
+    /// absent from the path.
+    pub captures: Vec<Option<(usize, usize)>>,
+}
+
+/// A pattern matching a path, with the text of its parameters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Captures<'a> {
+    pub token: PatternToken,
+    /// Parameter names with their text in the path, not percent-decoded, in the order of
+    /// `CompiledPattern::params`. `None` for parameters of optional groups absent from the path.
+    pub params: Vec<(&'a str, Option<&'a str>)>,
+}
+
+impl<'a> Captures<'a> {
+    /// The text captured by the parameter `name`, if present.
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.params
+            .iter()
+            .find(|&&(n, _)| n == name)
+            .and_then(|&(_, v)| v)
     }
 }
 
+/// Patterns arranged in a trie of segments, so that a path is matched segment by segment.
 #[derive(Clone, Debug)]
 pub struct CompiledPatternSet {
-    re_set: RegexSet,
-    map: VecMap<PatternToken>,
+    root: Node,
+    /// For naming the captures of matches.
+    patterns: FxHashMap<PatternToken, CompiledPattern>,
+    nfc: bool,
 }
 
 impl CompiledPatternSet {
     #[inline]
     pub fn is_match(&self, path: &str) -> bool {
-        check_path!(path);
-        let path = &path[1..];
-        self.re_set.is_match(path)
+        self.best(path).is_some()
     }
 
-    #[inline]
+    #[cfg(test)]
     pub fn matched_token(&self, path: &str) -> Option<PatternToken> {
-        check_path!(path);
-        let path = &path[1..];
-        self.re_set
-            .matches(path)
+        self.best(path).map(|m| m.token)
+    }
+
+    /// The most specific pattern matching `path`, with its captures.
+    pub fn find<'a>(&'a self, path: &'a str) -> Option<Captures<'a>> {
+        self.best(path).map(|m| Captures {
+            token: m.token,
+            params: self.patterns[&m.token].captures(path, &m.captures),
+        })
+    }
+
+    /// All patterns matching `path` with their captures, in more-specific-first order.
+    pub fn find_all<'a>(&'a self, path: &'a str) -> Vec<Captures<'a>> {
+        self.matches(path)
+            .into_iter()
+            .map(|m| Captures {
+                token: m.token,
+                params: self.patterns[&m.token].captures(path, &m.captures),
+            })
+            .collect()
+    }
+
+    /// Returns all matching patterns in more-specific-first order. Paths that don't start with `/`
+    /// match nothing.
+    pub fn matches(&self, path: &str) -> Vec<Match> {
+        let mut found = Found::All(Vec::new());
+        self.walk(path, &mut found);
+        let mut found = match found {
+            Found::All(found) => found,
+            Found::Best(..) => unreachable!(),
+        };
+        found.sort_by_key(|&(rank, _)| rank);
+        // wildcards may match in more than one way; the first found captures the most
+        found.dedup_by_key(|&mut (rank, _)| rank);
+        found.into_iter().map(|(_, m)| m).collect()
+    }
+
+    /// The first of `matches`, without collecting the others.
+    fn best(&self, path: &str) -> Option<Match> {
+        let mut found = Found::Best(None);
+        self.walk(path, &mut found);
+        match found {
+            Found::Best(best) => best.map(|(_, m)| m),
+            Found::All(..) => unreachable!(),
+        }
+    }
+
+    fn walk(&self, path: &str, found: &mut Found) {
+        check_path!(path, ());
+
+        let mut segments = Vec::new();
+        let mut start = 1;
+        for (i, _) in path.match_indices('/').skip(1) {
+            segments.push((start, i));
+            start = i + 1;
+        }
+        segments.push((start, path.len()));
+        let keys = segments
             .iter()
-            .next()
-            .and_then(|i| self.map.get(i).cloned())
+            .map(|&(start, end)| segment_key(&path[start..end], self.nfc))
+            .collect::<Vec<_>>();
+
+        self.root.collect(
+            &Walk {
+                path,
+                segments: &segments,
+                keys: &keys,
+            },
+            0,
+            &mut Vec::new(),
+            found,
+        );
+    }
+}
+
+/// Matches found while walking the trie, with their ranks.
+enum Found {
+    All(Vec<(usize, Match)>),
+    /// Only the match of the lowest rank, the first found among equals.
+    Best(Option<(usize, Match)>),
+}
+
+impl Found {
+    fn wants(&self, rank: usize) -> bool {
+        match *self {
+            Found::All(..) | Found::Best(None) => true,
+            Found::Best(Some((best, _))) => rank < best,
+        }
+    }
+
+    fn push(&mut self, rank: usize, m: Match) {
+        match *self {
+            Found::All(ref mut found) => found.push((rank, m)),
+            Found::Best(ref mut best) => *best = Some((rank, m)),
+        }
+    }
+}
+
+/// A pattern, or an alternative of one with optional groups, ending at a node.
+#[derive(Clone, Debug)]
+struct End {
+    /// Position in the `PatternSet`.
+    rank: usize,
+    token: PatternToken,
+    terminator: Option<Terminator>,
+    /// For alternatives, the number of parameters of the whole pattern and the indices of
+    /// those captured here.
+    slots: Option<(usize, Vec<usize>)>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Node {
+    /// Fixed segments, as given by `segment_key`.
+    fixed: FxHashMap<String, Node>,
+    /// Fixed segments of case-insensitive patterns, in ASCII lowercase.
+    folded: FxHashMap<String, Node>,
+    /// Parameter and mixed segments, with whether they're case-insensitive and the nodes
+    /// following them.
+    dynamic: Vec<(Segment, bool, SegmentMatcher, Node)>,
+    ends: Vec<End>,
+}
+
+/// A path being matched, split into the byte ranges of its segments.
+struct Walk<'a> {
+    path: &'a str,
+    segments: &'a [(usize, usize)],
+    /// The segments as given by `segment_key`, to look up fixed segments.
+    keys: &'a [Cow<'a, str>],
+}
+
+/// The form in which fixed segments of patterns and paths are compared: percent-decoded, unless
+/// that gives invalid UTF-8 or `/`, and NFC-normalized if `nfc`.
+fn segment_key<'a>(s: &'a str, nfc: bool) -> Cow<'a, str> {
+    let decoded = Decoding::default().decode(s).unwrap_or(Cow::Borrowed(s));
+    if nfc && !decoded.is_ascii() {
+        Cow::Owned(decoded.nfc().collect())
+    } else {
+        decoded
     }
 }
 
+impl Node {
+    fn insert(&mut self, segments: &[Segment], fold: bool, nfc: bool, end: End) {
+        let (seg, rest) = match segments.split_first() {
+            Some(x) => x,
+            None => return self.ends.push(end),
+        };
+        let next = match *seg {
+            Segment::Fixed(ref s) if fold => self.folded
+                .entry(segment_key(s, nfc).to_ascii_lowercase())
+                .or_insert_with(Node::default),
+            Segment::Fixed(ref s) => self.fixed
+                .entry(segment_key(s, nfc).into_owned())
+                .or_insert_with(Node::default),
+            _ => {
+                let i = match self.dynamic.iter().position(|d| d.0 == *seg && d.1 == fold) {
+                    Some(i) => i,
+                    None => {
+                        let m = SegmentMatcher::new(seg, fold, nfc);
+                        self.dynamic.push((seg.clone(), fold, m, Node::default()));
+                        self.dynamic.len() - 1
+                    }
+                };
+                &mut self.dynamic[i].3
+            }
+        };
+        next.insert(rest, fold, nfc, end)
+    }
+
+    /// Pushes patterns matching the path from its `i`th segment on, with their ranks.
+    fn collect(
+        &self,
+        walk: &Walk,
+        i: usize,
+        captures: &mut Vec<(usize, usize)>,
+        found: &mut Found,
+    ) {
+        let n = walk.segments.len();
+        for end in &self.ends {
+            if !found.wants(end.rank) {
+                continue;
+            }
+            let tail = match end.terminator {
+                None if i == n => None,
+                Some(Terminator::OptionalSlash)
+                    if i == n || (i + 1 == n && walk.segments[i].0 == walk.segments[i].1) =>
+                {
+                    None
+                }
+                Some(Terminator::Tail(..)) if i < n => Some((walk.segments[i].0, walk.path.len())),
+                _ => continue,
+            };
+            let mut captured = captures.iter().cloned().chain(tail).map(Some).collect();
+            if let Some((n, ref slots)) = end.slots {
+                let mut all = vec![None; n];
+                for (&slot, c) in slots.iter().zip(captured) {
+                    all[slot] = c;
+                }
+                captured = all;
+            }
+            let m = Match {
+                token: end.token,
+                captures: captured,
+            };
+            found.push(end.rank, m);
+        }
+
+        if i == n {
+            return;
+        }
+        let (start, end) = walk.segments[i];
+        let part = &walk.path[start..end];
+        let key = &*walk.keys[i];
+        if let Some(next) = self.fixed.get(key) {
+            next.collect(walk, i + 1, captures, found);
+        }
+        if !self.folded.is_empty() {
+            if let Some(next) = self.folded.get(&key.to_ascii_lowercase()) {
+                next.collect(walk, i + 1, captures, found);
+            }
+        }
+        for &(_, _, ref m, ref next) in &self.dynamic {
+            let len = captures.len();
+            if let SegmentMatcher::Wildcard = *m {
+                for j in (i + 1..n + 1).rev() {
+                    let end = walk.segments[j - 1].1;
+                    if start < end {
+                        captures.push((start, end));
+                        next.collect(walk, j, captures, found);
+                        captures.truncate(len);
+                    }
+                }
+            } else if m.captures(part, start, captures) {
+                next.collect(walk, i + 1, captures, found);
+            }
+            captures.truncate(len);
+        }
+    }
+}
+
+/// A parsed pattern. See the module documentation for the grammar.
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Default)]
 pub struct Pattern {
     segments: Vec<Segment>,
     terminator: Option<Terminator>,
+    /// Whether literal text matches regardless of ASCII case.
+    case_insensitive: bool,
 }
 
 impl Pattern {
@@ -157,142 +492,272 @@ impl Pattern {
         Default::default()
     }
 
+    /// Whether the pattern ends with `/?` or `/:name`, so that it can't be extended.
     pub fn terminated(&self) -> bool {
         self.terminator.is_some()
     }
 
-    pub fn push(&mut self, segment: Segment) {
-        if let Some(ref term) = self.terminator {
-            panic!(
-                r#"tried to push a segment to the pattern already terminated with "{:?}""#,
-                term.expr(),
-            );
-        }
-        if let Segment::Fixed(ref name) = segment {
-            assert!(
-                self.segments.iter().all(|s| {
-                    if let Segment::Fixed(ref s) = *s {
-                        s != name
-                    } else {
-                        true
-                    }
-                }),
-                "duplicated parameter name"
-            );
-        }
-        if let Some(&Segment::Fixed(ref s)) = self.segments.last() {
-            assert!(!s.is_empty(), "there cannot be empty segment");
-            // except in the last segment
-        }
-        self.segments.push(segment);
+    #[inline]
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
     }
 
-    /// Tests if this pattern matches with `path`.
-    #[cfg(test)]
-    pub fn is_match(&self, path: &str) -> bool {
-        use self::Segment::*;
-        use itertools::EitherOrBoth::*;
-        use itertools::Position::*;
-
-        check_path!(path);
+    #[inline]
+    pub fn terminator(&self) -> Option<&Terminator> {
+        self.terminator.as_ref()
+    }
 
-        let path = if self.terminator == Some(Terminator::OptionalSlash) && path.ends_with('/') {
-            &path[..path.len() - 1]
-        } else {
-            path
-        };
+    /// Makes fixed segments and the literal text of mixed ones match regardless of ASCII case.
+    /// Parameters still capture the path as it is.
+    pub fn set_case_insensitive(&mut self, yes: bool) {
+        self.case_insensitive = yes;
+    }
 
-        for p in path.split('/')
-            .skip(1)
-            .with_position()
-            .zip_longest(self.segments.iter())
-        {
-            match p {
-                Both(a, &Fixed(ref b)) => if a.into_inner() != b {
-                    return false;
-                },
-                Both(a, &Parameter(_, allow_empty)) => if !allow_empty && a.into_inner().is_empty()
-                {
-                    return false;
-                },
-                Left(_) if matches!(self.terminator, Some(Terminator::Tail(..))) => return true,
-                Left(_) | Right(_) => return false,
-            }
+    #[inline]
+    pub fn case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
 
-            if matches!(p, Both(Last(_), _))
-                && matches!(self.terminator, Some(Terminator::Tail(..)))
-            {
-                // `/foo/bar/:path` does not match `/foo/bar`
-                return path.ends_with('/');
-            }
+    /// The pattern as written, but without the `(?i)` prefix.
+    pub fn template(&self) -> String {
+        let s = self.to_string();
+        if self.case_insensitive {
+            s[CASE_INSENSITIVE.len()..].to_string()
+        } else {
+            s
         }
-        true
     }
 
-    fn to_re_string(&self) -> String {
-        use self::Segment::*;
-        use itertools::Position::*;
+    /// Parameters of the segments in order, as `(name, allow_empty, constraint)`. Excludes `:tail`.
+    pub fn parameters<'a>(&'a self) -> Vec<(&'a str, bool, Option<&'a Constraint>)> {
+        let mut out = Vec::new();
+        push_parameters(&self.segments, &mut out);
+        out
+    }
 
-        let mut out = String::new();
-        out.push_str("(?-u:^");
+    /// Number of parameters, including the one captured by `:tail`.
+    pub fn parameter_count(&self) -> usize {
+        self.parameters().len() + matches!(self.terminator, Some(Terminator::Tail(..))) as usize
+    }
 
-        for seg in self.segments.iter().with_position() {
-            match *seg.into_inner() {
-                Fixed(ref s) => {
-                    out.push_str(&regex::escape(s));
-                }
-                Parameter(_, allow_empty) => {
-                    out.push_str(if allow_empty { "([^/]*)" } else { "([^/]+)" });
+    /// The patterns without optional groups matching what this one does, one for each choice
+    /// of groups to keep, longest first. Just this pattern if it has no groups.
+    pub fn alternatives(&self) -> Vec<Pattern> {
+        fn expand(segments: &[Segment]) -> Vec<Vec<Segment>> {
+            let mut out = vec![vec![]];
+            for seg in segments {
+                if let Segment::Optional(ref group) = *seg {
+                    let group = expand(group);
+                    out = out.into_iter()
+                        .flat_map(|head| {
+                            let mut alts = group
+                                .iter()
+                                .map(|g| head.iter().chain(g).cloned().collect())
+                                .collect::<Vec<_>>();
+                            alts.push(head);
+                            alts
+                        })
+                        .collect();
+                } else {
+                    for alt in &mut out {
+                        alt.push(seg.clone());
+                    }
                 }
             }
+            out
+        }
 
-            if matches!(seg, First(..) | Middle(..)) {
-                out.push('/');
-            }
+        expand(&self.segments)
+            .into_iter()
+            .map(|segments| Pattern {
+                segments,
+                terminator: self.terminator.clone(),
+                case_insensitive: self.case_insensitive,
+            })
+            .collect()
+    }
+
+    /// This pattern without its optional groups.
+    fn required<'a>(&'a self) -> Cow<'a, Pattern> {
+        if self.segments.iter().any(Segment::is_optional) {
+            Cow::Owned(Pattern {
+                segments: self.segments
+                    .iter()
+                    .filter(|s| !s.is_optional())
+                    .cloned()
+                    .collect(),
+                terminator: self.terminator.clone(),
+                case_insensitive: self.case_insensitive,
+            })
+        } else {
+            Cow::Borrowed(self)
         }
+    }
 
-        if let Some(t) = self.terminator.as_ref() {
-            if !self.segments.is_empty() {
-                out.push('/');
+    fn push(&mut self, segment: Segment) -> Result<(), PatternErrorKind> {
+        if let Some(ref term) = self.terminator {
+            return Err(PatternErrorKind::Terminated(term.expr().into_owned()));
+        }
+        if let Some(&Segment::Fixed(ref s)) = self.segments.last() {
+            if s.is_empty() {
+                // only the last segment may be empty
+                return Err(PatternErrorKind::EmptySegment);
             }
-            match *t {
-                Terminator::OptionalSlash => {
-                    out.push('?');
-                }
-                Terminator::Tail(..) => out.push_str(r"([^\s]*)"),
+        }
+        let new = Pattern {
+            segments: vec![segment],
+            ..Pattern::new()
+        };
+        let names = new.parameters();
+        for (i, &(name, ..)) in names.iter().enumerate() {
+            self.check_name(name)?;
+            if names[..i].iter().any(|p| p.0 == name) {
+                return Err(PatternErrorKind::DuplicateParameter(name.to_string()));
             }
         }
+        self.segments.extend(new.segments);
+        Ok(())
+    }
 
-
-        out.push_str("$)");
-        out
+    fn check_name(&self, name: &str) -> Result<(), PatternErrorKind> {
+        let tail = match self.terminator {
+            Some(Terminator::Tail(ref tail)) => Some(&tail[..]),
+            _ => None,
+        };
+        if self.parameters().iter().any(|p| p.0 == name) || tail == Some(name) {
+            Err(PatternErrorKind::DuplicateParameter(name.to_string()))
+        } else {
+            Ok(())
+        }
     }
 
-    pub fn compile(&self) -> CompiledPattern {
-        struct ParamNamesIter<'a>(&'a [Segment], usize);
+    /// Tests if this pattern matches with `path`.
+    pub fn is_match(&self, path: &str) -> bool {
+        self.explain(path).is_ok()
+    }
 
-        impl<'a> Iterator for ParamNamesIter<'a> {
-            type Item = String;
+    /// Tests if this pattern matches with `path`, and tells why if it doesn't.
+    pub fn explain(&self, path: &str) -> Result<(), Mismatch> {
+        self.explain_with(path, false)
+    }
 
-            fn next(&mut self) -> Option<String> {
-                if self.1 >= self.0.len() {
-                    return None;
+    /// Like `explain`, but fixed segments and paths are compared after NFC normalization if
+    /// `nfc`, as by `PatternSet::compile_with`.
+    pub fn explain_with(&self, path: &str, nfc: bool) -> Result<(), Mismatch> {
+        check_path!(path, Err(Mismatch::NotAbsolute));
+
+        if self.segments.iter().any(Segment::is_optional) {
+            let mut errors = Vec::new();
+            for alt in self.alternatives() {
+                match alt.explain_with(path, nfc) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => errors.push(e),
                 }
+            }
+            // the longest alternative which the path isn't too short for tells the most
+            let i = errors
+                .iter()
+                .position(|e| *e != Mismatch::TooShort)
+                .unwrap_or(0);
+            return Err(errors.swap_remove(i));
+        }
 
-                for i in self.1..self.0.len() {
-                    if let Segment::Parameter(ref name, _) = self.0[i] {
-                        self.1 = i + 1;
-                        return Some(name.to_string());
+        let path = if self.terminator == Some(Terminator::OptionalSlash) && path.ends_with('/') {
+            &path[..path.len() - 1]
+        } else {
+            path
+        };
+        let parts = path.split('/').skip(1).collect::<Vec<_>>();
+        self.explain_from(0, &parts, 0, nfc)
+    }
+
+    /// Matches the segments from the `i`th on with `parts` from the `at`th on.
+    fn explain_from(&self, i: usize, parts: &[&str], at: usize, nfc: bool) -> Result<(), Mismatch> {
+        let tail = matches!(self.terminator, Some(Terminator::Tail(..)));
+        let seg = match self.segments.get(i) {
+            Some(seg) => seg,
+            None => {
+                return if parts.len() > at {
+                    if tail {
+                        Ok(())
+                    } else if parts.len() == at + 1 && parts[at].is_empty() {
+                        Err(Mismatch::UnexpectedTrailingSlash)
+                    } else {
+                        Err(Mismatch::TooLong)
                     }
+                } else if tail {
+                    // `/foo/bar/:path` does not match `/foo/bar`
+                    Err(Mismatch::MissingTrailingSlash)
+                } else {
+                    Ok(())
+                }
+            }
+        };
+        let part = match parts.get(at) {
+            Some(part) => *part,
+            None if i + 1 == self.segments.len() && *seg == Segment::Fixed(String::new()) => {
+                return Err(Mismatch::MissingTrailingSlash)
+            }
+            None => return Err(Mismatch::TooShort),
+        };
+        match *seg {
+            Segment::Fixed(ref s) => if !eq_literal(
+                &segment_key(s, nfc),
+                &segment_key(part, nfc),
+                self.case_insensitive,
+            ) {
+                return Err(Mismatch::Segment {
+                    index: at,
+                    expected: s.clone(),
+                    found: part.to_string(),
+                });
+            },
+            Segment::Parameter(ref name, _, Some(ref c)) => if !c.is_match(part) {
+                return Err(Mismatch::Constraint {
+                    index: at,
+                    name: name.clone(),
+                    constraint: c.clone(),
+                });
+            },
+            Segment::Parameter(ref name, allow_empty, None) => if !allow_empty && part.is_empty() {
+                return Err(Mismatch::EmptyParameter {
+                    index: at,
+                    name: name.clone(),
+                });
+            },
+            Segment::Mixed(..) => if !seg.is_match(part, self.case_insensitive, nfc) {
+                return Err(Mismatch::Segment {
+                    index: at,
+                    expected: seg.to_string(),
+                    found: part.to_string(),
+                });
+            },
+            Segment::Optional(..) => unreachable!("optional groups are explained as alternatives"),
+            Segment::Wildcard(ref name) => {
+                // captures spanning segments first, then just this one, which tells why if
+                // nothing matches
+                for end in (at + 2..parts.len() + 1).rev() {
+                    if self.explain_from(i + 1, parts, end, nfc).is_ok() {
+                        return Ok(());
+                    }
+                }
+                if part.is_empty() {
+                    return Err(Mismatch::EmptyParameter {
+                        index: at,
+                        name: name.clone(),
+                    });
                 }
-                self.1 = self.0.len();
-                None
             }
         }
+        self.explain_from(i + 1, parts, at + 1, nfc)
+    }
 
+    /// Lists the parameters, to name the captures of a `Match`.
+    pub fn compile(&self) -> CompiledPattern {
         CompiledPattern {
-            re: Regex::new(&self.to_re_string()).expect("regex syntax error"),
-            params: ParamNamesIter(&self.segments, 0)
+            params: self.parameters()
+                .into_iter()
+                .map(|(name, ..)| name.to_string())
                 .chain(if let Some(Terminator::Tail(ref name)) = self.terminator {
                     Some(name.to_string())
                 } else {
@@ -304,12 +769,41 @@ impl Pattern {
 }
 
 
+fn push_parameters<'a>(
+    segments: &'a [Segment],
+    out: &mut Vec<(&'a str, bool, Option<&'a Constraint>)>,
+) {
+    for seg in segments {
+        match *seg {
+            Segment::Fixed(..) => (),
+            Segment::Parameter(ref name, allow_empty, ref c) => {
+                out.push((&name[..], allow_empty, c.as_ref()))
+            }
+            Segment::Mixed(ref parts) => for part in parts {
+                if let Part::Parameter(ref name, allow_empty, ref c) = *part {
+                    out.push((&name[..], allow_empty, c.as_ref()));
+                }
+            },
+            Segment::Wildcard(ref name) => out.push((&name[..], false, None)),
+            Segment::Optional(ref group) => push_parameters(group, out),
+        }
+    }
+}
+
 impl Ord for Pattern {
     fn cmp(&self, other: &Pattern) -> Ordering {
-        use self::Segment::*;
+        // optional groups rank as if they were absent, and then after no group
+        self.required()
+            .cmp_required(&other.required())
+            .then_with(|| cmp_segments(&self.segments, &other.segments, false))
+            .then(self.segments.len().cmp(&other.segments.len()))
+    }
+}
 
+impl Pattern {
+    fn cmp_required(&self, other: &Pattern) -> Ordering {
         fn is_empty_fixed_segment(s: &Segment) -> bool {
-            if let Fixed(ref s) = *s {
+            if let Segment::Fixed(ref s) = *s {
                 s.is_empty()
             } else {
                 false
@@ -327,33 +821,103 @@ impl Ord for Pattern {
             .count();
 
         if len_self == len_other {
-            for (a, b) in self.segments.iter().zip(other.segments.iter()) {
-                match (a, b) {
-                    // {a?} ∋ {a}
-                    (&Parameter(ref a, oa), &Parameter(ref b, ob)) => {
-                        let c = oa.cmp(&ob).then(a.cmp(b));
-                        if c != Ordering::Equal {
-                            return c;
-                        }
-                    }
-                    (&Fixed(ref a), &Fixed(ref b)) => {
-                        let c = a.cmp(b);
-                        if c != Ordering::Equal {
-                            return c;
-                        }
-                    }
-                    (&Fixed(..), &Parameter(..)) => return Ordering::Less,
-                    (&Parameter(..), &Fixed(..)) => return Ordering::Greater,
-                }
-            }
-
-            self.terminator.cmp(&other.terminator)
+            // literal text is compared regardless of case first, so that case-sensitive patterns
+            // precede case-insensitive ones matching the same paths
+            cmp_segments(&self.segments, &other.segments, true)
+                .then_with(|| self.terminator.cmp(&other.terminator))
+                .then(self.case_insensitive.cmp(&other.case_insensitive))
+                .then_with(|| cmp_segments(&self.segments, &other.segments, false))
         } else {
             len_other.cmp(&len_self)
         }
     }
 }
 
+/// Compares segment by segment, ignoring any excess segments of the longer one.
+fn cmp_segments(a: &[Segment], b: &[Segment], fold: bool) -> Ordering {
+    use self::Segment::*;
+
+    for (a, b) in a.iter().zip(b.iter()) {
+        // fixed, then mixed, then parameter, then wildcard segments, then optional groups
+        let c = match (a, b) {
+            (&Optional(ref a), &Optional(ref b)) => {
+                cmp_segments(a, b, fold).then(a.len().cmp(&b.len()))
+            }
+            (&Optional(..), _) => Ordering::Greater,
+            (_, &Optional(..)) => Ordering::Less,
+            (&Parameter(ref a, oa, ref ca), &Parameter(ref b, ob, ref cb)) => {
+                cmp_parameters((a, oa, ca), (b, ob, cb))
+            }
+            (&Fixed(ref a), &Fixed(ref b)) => cmp_literal(a, b, fold),
+            (&Mixed(ref a), &Mixed(ref b)) => cmp_mixed(a, b, fold),
+            (&Wildcard(ref a), &Wildcard(ref b)) => a.cmp(b),
+            (&Fixed(..), _) | (&Mixed(..), &Parameter(..)) | (_, &Wildcard(..)) => {
+                Ordering::Less
+            }
+            (_, &Fixed(..)) | (&Parameter(..), &Mixed(..)) | (&Wildcard(..), _) => {
+                Ordering::Greater
+            }
+        };
+        if c != Ordering::Equal {
+            return c;
+        }
+    }
+    Ordering::Equal
+}
+
+fn cmp_literal(a: &str, b: &str, fold: bool) -> Ordering {
+    if fold {
+        let lower = |s: &str| s.bytes().map(|b| b.to_ascii_lowercase()).collect::<Vec<_>>();
+        lower(a).cmp(&lower(b))
+    } else {
+        a.cmp(b)
+    }
+}
+
+/// `{a?} ∋ {a} ∋ {a:constraint}`
+fn cmp_parameters(
+    (a, oa, ca): (&String, bool, &Option<Constraint>),
+    (b, ob, cb): (&String, bool, &Option<Constraint>),
+) -> Ordering {
+    ca.is_none()
+        .cmp(&cb.is_none())
+        .then(oa.cmp(&ob))
+        .then(ca.cmp(cb))
+        .then(a.cmp(b))
+}
+
+/// Segments with more literal text come first, so `{name}.tar.gz` precedes `{name}.{ext}`.
+fn cmp_mixed(a: &[Part], b: &[Part], fold: bool) -> Ordering {
+    fn literal_len(parts: &[Part]) -> usize {
+        parts
+            .iter()
+            .map(|p| match *p {
+                Part::Literal(ref s) => s.len(),
+                Part::Parameter(..) => 0,
+            })
+            .sum()
+    }
+
+    let c = literal_len(b).cmp(&literal_len(a));
+    if c != Ordering::Equal {
+        return c;
+    }
+    for (a, b) in a.iter().zip(b.iter()) {
+        let c = match (a, b) {
+            (&Part::Literal(ref a), &Part::Literal(ref b)) => cmp_literal(a, b, fold),
+            (&Part::Parameter(ref a, oa, ref ca), &Part::Parameter(ref b, ob, ref cb)) => {
+                cmp_parameters((a, oa, ca), (b, ob, cb))
+            }
+            (&Part::Literal(..), &Part::Parameter(..)) => Ordering::Less,
+            (&Part::Parameter(..), &Part::Literal(..)) => Ordering::Greater,
+        };
+        if c != Ordering::Equal {
+            return c;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
 impl PartialOrd for Pattern {
     #[inline]
     fn partial_cmp(&self, other: &Pattern) -> Option<Ordering> {
@@ -363,42 +927,96 @@ impl PartialOrd for Pattern {
 
 impl Display for Pattern {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        use self::Segment::*;
-        use itertools::Position::*;
-
-        write!(f, "/")?;
-        for s in self.segments.iter().with_position() {
-            match *s.into_inner() {
-                Fixed(ref s) => write!(f, "{}", s)?,
-                Parameter(ref name, allow_empty) => {
-                    write!(f, "{{{}{}}}", name, if allow_empty { "?" } else { "" })?;
-                }
-            }
-            if matches!(s, First(..) | Middle(..)) {
-                write!(f, "/")?;
+        if self.case_insensitive {
+            write!(f, "{}", CASE_INSENSITIVE)?;
+        }
+        for seg in &self.segments {
+            if seg.is_optional() {
+                write!(f, "{}", seg)?;
+            } else {
+                write!(f, "/{}", seg)?;
             }
         }
         match self.terminator {
-            Some(Terminator::OptionalSlash) => {
-                write!(f, "/?")?;
-            }
-            Some(Terminator::Tail(ref name)) => {
-                write!(f, "/:{}", name)?;
+            Some(Terminator::OptionalSlash) => write!(f, "/?"),
+            Some(Terminator::Tail(ref name)) => write!(f, "/:{}", name),
+            None if self.segments.is_empty() => write!(f, "/"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Why a `Pattern` doesn't match a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    /// A fixed segment differs. `index` counts segments after the leading `/`.
+    Segment {
+        index: usize,
+        expected: String,
+        found: String,
+    },
+    /// A `{name}` parameter would capture an empty segment.
+    EmptyParameter { index: usize, name: String },
+    /// The segment doesn't satisfy the constraint of a parameter.
+    Constraint {
+        index: usize,
+        name: String,
+        constraint: Constraint,
+    },
+    /// The path ends before the pattern does.
+    TooShort,
+    /// The path goes on after the pattern ends.
+    TooLong,
+    MissingTrailingSlash,
+    UnexpectedTrailingSlash,
+    /// The path doesn't start with `/`.
+    NotAbsolute,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Mismatch::Segment {
+                index,
+                ref expected,
+                ref found,
+            } => write!(
+                f,
+                "segment {} is {:?}, expected {:?}",
+                index, found, expected
+            ),
+            Mismatch::EmptyParameter { index, ref name } => {
+                write!(f, "parameter {{{}}} at segment {} is empty", name, index)
             }
-            _ => (),
+            Mismatch::Constraint {
+                index,
+                ref name,
+                ref constraint,
+            } => write!(
+                f,
+                "parameter {{{}}} at segment {} is not {}",
+                name, index, constraint
+            ),
+            Mismatch::TooShort => write!(f, "path is shorter than the pattern"),
+            Mismatch::TooLong => write!(f, "path is longer than the pattern"),
+            Mismatch::MissingTrailingSlash => write!(f, "path lacks the trailing slash"),
+            Mismatch::UnexpectedTrailingSlash => write!(f, "path has a trailing slash"),
+            Mismatch::NotAbsolute => write!(f, "path doesn't start with '/'"),
         }
-        Ok(())
     }
 }
 
+/// What may follow the segments of a pattern.
 #[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
-enum Terminator {
+pub enum Terminator {
+    /// `/?`
     OptionalSlash,
+    /// `/:name`, capturing the rest of the path.
     Tail(String),
 }
 
 impl Terminator {
-    fn expr(&self) -> Cow<'static, str> {
+    pub(crate) fn expr(&self) -> Cow<'static, str> {
         match *self {
             Terminator::OptionalSlash => Cow::from("/?"),
             Terminator::Tail(ref name) => Cow::from(format!(":{}", name)),
@@ -406,38 +1024,615 @@ impl Terminator {
     }
 }
 
+/// A piece of a pattern between slashes.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Segment {
+    /// Literal text, as written in the pattern.
     Fixed(String),
-    Parameter(String, bool),
+    /// `{name}`, `{name?}` which may be empty, or `{name:constraint}`.
+    Parameter(String, bool, Option<Constraint>),
+    /// Literal text and parameters, like `{name}.{ext}`. Parameters are never adjacent.
+    Mixed(Vec<Part>),
+    /// `:name` before the last segment, or `**` named `**`. Captures one or more segments,
+    /// preferring more, but never an empty string.
+    Wildcard(String),
+    /// `[/a/b]`, segments which are either all present or all absent. Never empty, and never
+    /// contains empty fixed segments.
+    Optional(Vec<Segment>),
 }
 
-impl FromStr for Segment {
-    type Err = ();
+/// A piece of a `Segment::Mixed`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Part {
+    Literal(String),
+    Parameter(String, bool, Option<Constraint>),
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.starts_with(':') {
-            panic!("`:path` must be the last segment"); // TODO: err
+fn fmt_parameter(
+    f: &mut Formatter,
+    name: &str,
+    allow_empty: bool,
+    constraint: Option<&Constraint>,
+) -> fmt::Result {
+    match constraint {
+        Some(c) => write!(f, "{{{}:{}}}", name, c),
+        None => write!(f, "{{{}{}}}", name, if allow_empty { "?" } else { "" }),
+    }
+}
+
+impl Segment {
+    /// Tests if this segment matches with `part`, a segment of a path.
+    fn is_match(&self, part: &str, fold: bool, nfc: bool) -> bool {
+        SegmentMatcher::new(self, fold, nfc).captures(part, 0, &mut Vec::new())
+    }
+
+    fn is_optional(&self) -> bool {
+        matches!(*self, Segment::Optional(..))
+    }
+}
+
+impl Display for Segment {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Segment::Fixed(ref s) => write!(f, "{}", s),
+            Segment::Parameter(ref name, allow_empty, ref c) => {
+                fmt_parameter(f, name, allow_empty, c.as_ref())
+            }
+            Segment::Mixed(ref parts) => {
+                for part in parts {
+                    match *part {
+                        Part::Literal(ref s) => write!(f, "{}", s)?,
+                        Part::Parameter(ref name, allow_empty, ref c) => {
+                            fmt_parameter(f, name, allow_empty, c.as_ref())?
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Segment::Wildcard(ref name) if name == "**" => write!(f, "**"),
+            Segment::Wildcard(ref name) => write!(f, ":{}", name),
+            Segment::Optional(ref group) => {
+                write!(f, "[")?;
+                for seg in group {
+                    if seg.is_optional() {
+                        write!(f, "{}", seg)?;
+                    } else {
+                        write!(f, "/{}", seg)?;
+                    }
+                }
+                write!(f, "]")
+            }
         }
-        if s.starts_with('{') && s.ends_with('}') {
-            let s = &s[1..s.len() - 1];
-            let (s, allow_empty) = if s.ends_with('?') {
-                (&s[..s.len() - 1], true)
-            } else {
-                (s, false)
+    }
+}
+
+/// A `Segment` prepared for matching segments of paths.
+#[derive(Clone, Debug)]
+enum SegmentMatcher {
+    Parameter(ParameterMatcher),
+    /// Fixed and mixed segments.
+    Parts(Vec<PartMatcher>),
+    /// Matched by `Node::collect`, as it spans segments.
+    Wildcard,
+}
+
+#[derive(Clone, Debug)]
+enum PartMatcher {
+    /// Text as given by `segment_key`, and how it's compared with paths.
+    Literal(String, LiteralMatch),
+    Parameter(ParameterMatcher),
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct LiteralMatch {
+    /// Regardless of ASCII case.
+    fold: bool,
+    /// After NFC normalization.
+    nfc: bool,
+}
+
+impl SegmentMatcher {
+    fn new(seg: &Segment, fold: bool, nfc: bool) -> Self {
+        let how = LiteralMatch { fold, nfc };
+        match *seg {
+            Segment::Fixed(ref s) => SegmentMatcher::Parts(vec![
+                PartMatcher::Literal(segment_key(s, nfc).into_owned(), how),
+            ]),
+            Segment::Parameter(_, allow_empty, ref c) => {
+                SegmentMatcher::Parameter(ParameterMatcher::new(allow_empty, c.as_ref()))
+            }
+            Segment::Mixed(ref parts) => SegmentMatcher::Parts(
+                parts
+                    .iter()
+                    .map(|p| match *p {
+                        Part::Literal(ref s) => {
+                            PartMatcher::Literal(segment_key(s, nfc).into_owned(), how)
+                        }
+                        Part::Parameter(_, allow_empty, ref c) => {
+                            PartMatcher::Parameter(ParameterMatcher::new(allow_empty, c.as_ref()))
+                        }
+                    })
+                    .collect(),
+            ),
+            Segment::Wildcard(..) => SegmentMatcher::Wildcard,
+            Segment::Optional(..) => unreachable!("optional groups are matched as alternatives"),
+        }
+    }
+
+    /// Tests if `part`, which starts at `offset` in the path, matches. If it does, the ranges of
+    /// its parameters are pushed to `captures`.
+    fn captures(&self, part: &str, offset: usize, captures: &mut Vec<(usize, usize)>) -> bool {
+        match *self {
+            SegmentMatcher::Parameter(ref p) => {
+                let ok = p.accepts(part);
+                if ok {
+                    captures.push((offset, offset + part.len()));
+                }
+                ok
+            }
+            SegmentMatcher::Parts(ref parts) => match_parts(parts, part, offset, captures),
+            SegmentMatcher::Wildcard => {
+                // a wildcard spanning just this segment
+                let ok = !part.is_empty();
+                if ok {
+                    captures.push((offset, offset + part.len()));
+                }
+                ok
+            }
+        }
+    }
+}
+
+/// Matches `parts` with the whole of `s`, giving earlier parameters the longest values possible.
+fn match_parts(
+    parts: &[PartMatcher],
+    s: &str,
+    offset: usize,
+    captures: &mut Vec<(usize, usize)>,
+) -> bool {
+    let (first, rest) = match parts.split_first() {
+        Some(x) => x,
+        None => return s.is_empty(),
+    };
+    match *first {
+        PartMatcher::Literal(ref l, how) => match literal_prefix(s, l, how) {
+            Some(n) => match_parts(rest, &s[n..], offset + n, captures),
+            None => false,
+        },
+        PartMatcher::Parameter(ref p) => {
+            // parameters are followed by a literal, if anything
+            let (next, how) = match rest.first() {
+                Some(&PartMatcher::Literal(ref l, how)) => (&l[..], how),
+                _ => ("", LiteralMatch::default()),
             };
-            Ok(Segment::Parameter(s.to_string(), allow_empty))
+            for end in (0..s.len() + 1).rev() {
+                if !s.is_char_boundary(end) || literal_prefix(&s[end..], next, how).is_none()
+                    || !p.accepts(&s[..end])
+                {
+                    continue;
+                }
+                captures.push((offset, offset + end));
+                if match_parts(rest, &s[end..], offset + end, captures) {
+                    return true;
+                }
+                captures.pop();
+            }
+            false
+        }
+    }
+}
+
+/// The length of the start of `s`, a piece of a path, that decodes to `literal`, which is given
+/// by `segment_key`.
+fn literal_prefix(s: &str, literal: &str, how: LiteralMatch) -> Option<usize> {
+    if starts_with(s, literal, how.fold) {
+        return Some(literal.len());
+    }
+    if !s.contains('%') && (s.is_ascii() || !how.nfc) {
+        // `s` is its own key
+        return None;
+    }
+    (1..s.len() + 1)
+        .filter(|&end| s.is_char_boundary(end))
+        .find(|&end| eq_literal(literal, &segment_key(&s[..end], how.nfc), how.fold))
+}
+
+fn eq_literal(literal: &str, s: &str, fold: bool) -> bool {
+    if fold {
+        literal.eq_ignore_ascii_case(s)
+    } else {
+        literal == s
+    }
+}
+
+fn starts_with(s: &str, literal: &str, fold: bool) -> bool {
+    s.len() >= literal.len() && s.is_char_boundary(literal.len())
+        && eq_literal(literal, &s[..literal.len()], fold)
+}
+
+#[derive(Clone, Debug)]
+struct ParameterMatcher {
+    allow_empty: bool,
+    constraint: Option<Constraint>,
+    /// `Constraint::Regex`, anchored at both ends.
+    re: Option<Regex>,
+}
+
+impl ParameterMatcher {
+    fn new(allow_empty: bool, constraint: Option<&Constraint>) -> Self {
+        let re = match constraint {
+            Some(&Constraint::Regex(ref re)) => Some(constraint_regex(re)),
+            _ => None,
+        };
+        ParameterMatcher {
+            allow_empty,
+            constraint: constraint.cloned(),
+            re,
+        }
+    }
+
+    fn accepts(&self, value: &str) -> bool {
+        let constraint = match self.constraint {
+            Some(ref c) => c,
+            None => return self.allow_empty || !value.is_empty(),
+        };
+        // constraints see the value that handlers get, except that `%2F` stays encoded
+        let value = Decoding::default()
+            .decode(value)
+            .unwrap_or(Cow::Borrowed(value));
+        match *constraint {
+            Constraint::Integer(ref ty) => is_integer(ty, &value),
+            Constraint::Uuid => is_uuid(&value),
+            Constraint::Regex(..) => {
+                !value.contains('/') && self.re.as_ref().map_or(false, |re| re.is_match(&value))
+            }
+        }
+    }
+}
+
+thread_local! {
+    /// Compiled `Constraint::Regex`es, so that explaining a match doesn't compile them again.
+    static REGEXES: RefCell<HashMap<String, Regex>> = RefCell::new(HashMap::new());
+}
+
+/// `re` anchored at both ends.
+fn constraint_regex(re: &str) -> Regex {
+    REGEXES.with(|cache| {
+        cache
+            .borrow_mut()
+            .entry(re.to_string())
+            .or_insert_with(|| Regex::new(&format!("^(?:{})$", re)).expect("regex syntax error"))
+            .clone()
+    })
+}
+
+/// Decimal digits with an optional `-` for signed types, in the range of `ty`.
+fn is_integer(ty: &str, value: &str) -> bool {
+    let digits = if ty.starts_with('i') && value.starts_with('-') {
+        &value[1..]
+    } else {
+        value
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(10)) {
+        return false;
+    }
+    match ty {
+        "u8" => value.parse::<u8>().is_ok(),
+        "u16" => value.parse::<u16>().is_ok(),
+        "u32" => value.parse::<u32>().is_ok(),
+        "u64" => value.parse::<u64>().is_ok(),
+        "usize" => value.parse::<usize>().is_ok(),
+        "i8" => value.parse::<i8>().is_ok(),
+        "i16" => value.parse::<i16>().is_ok(),
+        "i32" => value.parse::<i32>().is_ok(),
+        "i64" => value.parse::<i64>().is_ok(),
+        "isize" => value.parse::<isize>().is_ok(),
+        _ => unreachable!(),
+    }
+}
+
+/// `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx` in hexadecimal digits of either case.
+fn is_uuid(value: &str) -> bool {
+    value.len() == 36 && value.char_indices().all(|(i, c)| match i {
+        8 | 13 | 18 | 23 => c == '-',
+        _ => c.is_digit(16),
+    })
+}
+
+/// Restricts the values of a parameter. Segments failing it don't match the pattern.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Constraint {
+    /// A primitive integer type like `u64`, checked by parsing.
+    Integer(String),
+    Uuid,
+    /// A regular expression, matched against the whole percent-decoded segment. It must not
+    /// contain capture groups or `/`.
+    Regex(String),
+}
+
+const INTEGER_TYPES: &'static [&'static str] = &[
+    "u8", "u16", "u32", "u64", "usize", "i8", "i16", "i32", "i64", "isize"
+];
+
+impl Constraint {
+    /// Tests if `value`, a whole segment as it appears in the path, satisfies the constraint once
+    /// percent-decoded.
+    pub fn is_match(&self, value: &str) -> bool {
+        ParameterMatcher::new(false, Some(self)).accepts(value)
+    }
+}
+
+impl FromStr for Constraint {
+    type Err = PatternError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if INTEGER_TYPES.contains(&s) {
+            return Ok(Constraint::Integer(s.to_string()));
+        }
+        if s == "uuid" {
+            return Ok(Constraint::Uuid);
+        }
+        match Regex::new(&format!("^(?:{})$", s)) {
+            Ok(ref re) if !s.is_empty() && re.captures_len() == 1 => {
+                Ok(Constraint::Regex(s.to_string()))
+            }
+            _ => Err(PatternError::new(
+                PatternErrorKind::InvalidConstraint(s.to_string()),
+                0,
+            )),
+        }
+    }
+}
+
+impl Display for Constraint {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Constraint::Integer(ref ty) => write!(f, "{}", ty),
+            Constraint::Uuid => write!(f, "uuid"),
+            Constraint::Regex(ref re) => write!(f, "{}", re),
+        }
+    }
+}
+
+/// Why a pattern couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatternErrorKind {
+    /// A pattern ending with `/?` or `/:tail` was used as a mount prefix.
+    Terminated(String),
+    DuplicateParameter(String),
+    /// `//` anywhere but at the end of the pattern.
+    EmptySegment,
+    EmptyParameterName,
+    UnclosedBrace,
+    UnmatchedBrace,
+    /// `{a}{b}`, which can't be split.
+    AdjacentParameters,
+    InvalidConstraint(String),
+    /// `{name?:constraint}`; the constraint decides whether the parameter may be empty.
+    OptionalConstraint,
+    /// A character not allowed in a URL path, which must be percent-encoded.
+    IllegalCharacter(char),
+    /// `%` not followed by two hexadecimal digits.
+    InvalidPercentEncoding,
+    UnclosedGroup,
+    UnmatchedGroup,
+    /// An optional group not made of whole segments, like `/a[b]` or `/[/a]b`.
+    InvalidGroup,
+    /// A route's handler takes a different number of parameters than the pattern has.
+    ParameterCount { pattern: usize, handler: usize },
+}
+
+impl Display for PatternErrorKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use self::PatternErrorKind::*;
+
+        match *self {
+            Terminated(ref term) => write!(f, "pattern is terminated with {:?}", term),
+            DuplicateParameter(ref name) => write!(f, "duplicated parameter name {:?}", name),
+            EmptySegment => write!(f, "there cannot be empty segment"),
+            EmptyParameterName => write!(f, "empty parameter name"),
+            UnclosedBrace => write!(f, "unclosed `{{`"),
+            UnmatchedBrace => write!(f, "unmatched `}}`"),
+            AdjacentParameters => write!(f, "parameters must be separated by literal text"),
+            InvalidConstraint(ref c) => write!(f, "invalid constraint {:?}", c),
+            OptionalConstraint => write!(f, "constrained parameters cannot be optional"),
+            IllegalCharacter(c) => write!(f, "illegal character {:?} in URL path", c),
+            InvalidPercentEncoding => write!(f, "invalid percent-encoding"),
+            UnclosedGroup => write!(f, "unclosed `[`"),
+            UnmatchedGroup => write!(f, "unmatched `]`"),
+            InvalidGroup => write!(f, "optional groups must consist of whole segments"),
+            ParameterCount { pattern, handler } => write!(
+                f,
+                "pattern has {} parameter(s), but the handler takes {}",
+                pattern, handler
+            ),
+        }
+    }
+}
+
+/// An invalid pattern, with the byte offset in the pattern where the problem was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatternError {
+    kind: PatternErrorKind,
+    offset: Option<usize>,
+}
+
+impl PatternError {
+    pub(crate) fn new(kind: PatternErrorKind, offset: usize) -> Self {
+        PatternError {
+            kind,
+            offset: Some(offset),
+        }
+    }
+
+    /// An error about the pattern as a whole, like `ParameterCount`.
+    pub(crate) fn without_offset(kind: PatternErrorKind) -> Self {
+        PatternError { kind, offset: None }
+    }
+
+    /// What is wrong with the pattern.
+    pub fn kind(&self) -> &PatternErrorKind {
+        &self.kind
+    }
+
+    /// The byte offset in the pattern where the problem was found. `None` if the problem isn't
+    /// at a particular place, as with `ParameterCount`.
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+}
+
+impl Display for PatternError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "{} at byte {}", self.kind, offset),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl Error for PatternError {
+    fn description(&self) -> &str {
+        "invalid pattern"
+    }
+}
+
+/// `pchar` of RFC 3986, except for `%` which is checked separately.
+fn is_path_char(c: char) -> bool {
+    match c {
+        'A'...'Z' | 'a'...'z' | '0'...'9' => true,
+        '-' | '.' | '_' | '~' => true,
+        '!' | '$' | '&' | '\'' | '(' | ')' | '*' | '+' | ',' | ';' | '=' => true,
+        ':' | '@' => true,
+        _ => false,
+    }
+}
+
+fn check_literal(s: &str, offset: usize) -> Result<(), PatternError> {
+    let bytes = s.as_bytes();
+    for (i, c) in s.char_indices() {
+        let err = |kind| Err(PatternError::new(kind, offset + i));
+        match c {
+            '%' => {
+                let hex = |j: usize| bytes.get(j).map_or(false, |b| (*b as char).is_digit(16));
+                if !hex(i + 1) || !hex(i + 2) {
+                    return err(PatternErrorKind::InvalidPercentEncoding);
+                }
+            }
+            '}' => return err(PatternErrorKind::UnmatchedBrace),
+            // matched percent-encoded
+            c if !c.is_ascii() => (),
+            c if !is_path_char(c) => return err(PatternErrorKind::IllegalCharacter(c)),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Parses `s`, which starts at `offset` in the whole pattern.
+fn parse_segment(s: &str, offset: usize) -> Result<Segment, PatternError> {
+    if s == "**" {
+        return Ok(Segment::Wildcard(s.to_string()));
+    }
+    if s.starts_with(':') {
+        if s.len() == 1 {
+            return Err(PatternError::new(PatternErrorKind::EmptyParameterName, offset + 1));
+        }
+        return Ok(Segment::Wildcard(s[1..].to_string()));
+    }
+    let mut parts = Vec::new();
+    let mut rest = s;
+    while !rest.is_empty() {
+        let pos = offset + s.len() - rest.len();
+        if rest.starts_with('{') {
+            // constraints may contain braces, as in `{n:[0-9]{4}}`
+            let mut depth = 0;
+            let end = rest.char_indices()
+                .find(|&(_, c)| {
+                    match c {
+                        '{' => depth += 1,
+                        '}' => depth -= 1,
+                        _ => (),
+                    }
+                    depth == 0
+                })
+                .ok_or_else(|| PatternError::new(PatternErrorKind::UnclosedBrace, pos))?
+                .0;
+            if let Some(&Part::Parameter(..)) = parts.last() {
+                return Err(PatternError::new(PatternErrorKind::AdjacentParameters, pos));
+            }
+            parts.push(parse_parameter(&rest[1..end], pos + 1)?);
+            rest = &rest[end + 1..];
         } else {
-            // TODO: reject illegal URL path
-            Ok(Segment::Fixed(s.to_string()))
+            let end = rest.find('{').unwrap_or_else(|| rest.len());
+            check_literal(&rest[..end], pos)?;
+            parts.push(Part::Literal(rest[..end].to_string()));
+            rest = &rest[end..];
         }
     }
+
+    match parts.len() {
+        0 => Ok(Segment::Fixed(String::new())),
+        1 => Ok(match parts.pop().unwrap() {
+            Part::Literal(s) => Segment::Fixed(s),
+            Part::Parameter(name, allow_empty, c) => Segment::Parameter(name, allow_empty, c),
+        }),
+        _ => Ok(Segment::Mixed(parts)),
+    }
+}
+
+impl FromStr for Segment {
+    type Err = PatternError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_segment(s, 0)
+    }
 }
 
+/// Parses the inside of `{...}`, which starts at `offset`.
+fn parse_parameter(s: &str, offset: usize) -> Result<Part, PatternError> {
+    let (s, constraint) = match s.find(':') {
+        Some(i) => {
+            let c = s[i + 1..]
+                .parse()
+                .map_err(|e: PatternError| PatternError::new(e.kind, offset + i + 1))?;
+            (&s[..i], Some(c))
+        }
+        None => (s, None),
+    };
+    let (s, allow_empty) = if s.ends_with('?') {
+        (&s[..s.len() - 1], true)
+    } else {
+        (s, false)
+    };
+    if s.is_empty() {
+        return Err(PatternError::new(PatternErrorKind::EmptyParameterName, offset));
+    }
+    if allow_empty && constraint.is_some() {
+        return Err(PatternError::new(PatternErrorKind::OptionalConstraint, offset));
+    }
+    Ok(Part::Parameter(s.to_string(), allow_empty, constraint))
+}
+
+/// Prefix of case-insensitive patterns.
+const CASE_INSENSITIVE: &'static str = "(?i)";
+
 impl FromStr for Pattern {
-    type Err = ();
+    type Err = PatternError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with(CASE_INSENSITIVE) {
+            let n = CASE_INSENSITIVE.len();
+            let mut pat: Pattern = s[n..]
+                .parse()
+                .map_err(|e: PatternError| PatternError {
+                    kind: e.kind,
+                    offset: e.offset.map(|offset| offset + n),
+                })?;
+            pat.case_insensitive = true;
+            return Ok(pat);
+        }
+
         let mut pat = Pattern::new();
         let mut term = None;
 
@@ -445,8 +1640,16 @@ impl FromStr for Pattern {
             term = Some(Terminator::OptionalSlash);
             &s[..s.len() - 2]
         } else if let Some(last) = s.rfind('/') {
-            if s[last + 1..].starts_with(':') {
-                term = Some(Terminator::Tail(s[last + 2..].to_string()));
+            // `[/:name]` is a wildcard in a group
+            if s[last + 1..].starts_with(':') && !s[last + 1..].contains(']') {
+                let name = &s[last + 2..];
+                if name.is_empty() {
+                    return Err(PatternError::new(
+                        PatternErrorKind::EmptyParameterName,
+                        last + 2,
+                    ));
+                }
+                term = Some(Terminator::Tail(name.to_string()));
                 &s[..last]
             } else {
                 s
@@ -455,44 +1658,119 @@ impl FromStr for Pattern {
             s
         };
 
-        let s = if s.starts_with('/') { &s[1..] } else { s };
+        let start = if s.starts_with('/') { 1 } else { 0 };
 
-        if !s.is_empty() || term.is_none() {
-            for p in s.split('/') {
-                pat.push(p.parse()?);
-            }
+        if s.len() > start || term.is_none() {
+            pat.parse_segments(&s[start..], start)?;
         }
 
+        if let Some(Terminator::Tail(ref name)) = term {
+            pat.check_name(name)
+                .map_err(|kind| PatternError::new(kind, s.len() + 2))?;
+        }
         pat.terminator = term;
 
         Ok(pat)
     }
 }
 
+impl Pattern {
+    /// Parses `s`, the segments after the leading `/` which start at `offset`.
+    fn parse_segments(&mut self, s: &str, offset: usize) -> Result<(), PatternError> {
+        let err = |kind, at| Err(PatternError::new(kind, offset + at));
+        let push = |pat: &mut Pattern, text: &str, at: usize| {
+            let seg = parse_segment(text, offset + at)?;
+            pat.push(seg).map_err(|kind| {
+                // point at the slash ending the empty segment
+                let at = if kind == PatternErrorKind::EmptySegment {
+                    offset + at - 1
+                } else {
+                    offset + at
+                };
+                PatternError::new(kind, at)
+            })
+        };
+
+        // indices of the first segments of open groups, with the offsets of their `[`
+        let mut groups = Vec::new();
+        // start of the segment being read, if not right after a group
+        let mut open = Some(0);
+        let mut depth = 0;
+        for (i, c) in s.char_indices() {
+            match c {
+                // constraints may contain `/` and brackets, as in `{n:[0-9]+}`
+                '{' => depth += 1,
+                '}' if depth > 0 => depth -= 1,
+                _ if depth > 0 => (),
+                '/' => {
+                    if let Some(start) = open {
+                        push(self, &s[start..i], start)?;
+                    }
+                    open = Some(i + 1);
+                }
+                '[' => {
+                    match open {
+                        Some(start) if start == i => return err(PatternErrorKind::EmptySegment, i),
+                        Some(start) => push(self, &s[start..i], start)?,
+                        None => (),
+                    }
+                    if !s[i + 1..].starts_with('/') {
+                        return err(PatternErrorKind::InvalidGroup, i);
+                    }
+                    groups.push((self.segments.len(), i));
+                    open = None;
+                }
+                ']' => {
+                    match open {
+                        Some(start) if start == i => return err(PatternErrorKind::EmptySegment, i),
+                        Some(start) => push(self, &s[start..i], start)?,
+                        None => (),
+                    }
+                    let (first, _) = match groups.pop() {
+                        Some(g) => g,
+                        None => return err(PatternErrorKind::UnmatchedGroup, i),
+                    };
+                    let group = self.segments.split_off(first);
+                    self.segments.push(Segment::Optional(group));
+                    if !s[i + 1..].is_empty() && !s[i + 1..].starts_with(|c| "/[]".contains(c)) {
+                        return err(PatternErrorKind::InvalidGroup, i + 1);
+                    }
+                    open = None;
+                }
+                _ => (),
+            }
+        }
+        if let Some(start) = open {
+            push(self, &s[start..], start)?;
+        }
+        match groups.pop() {
+            Some((_, at)) => err(PatternErrorKind::UnclosedGroup, at),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The names of the parameters of a pattern.
+#[derive(Clone, Debug)]
 pub struct CompiledPattern {
-    pub re: Regex,
+    /// In the order they appear in the pattern, the `/:name` tail last.
     pub params: Vec<String>,
 }
 
 impl CompiledPattern {
-    // pub fn is_match(&self, path: &str) -> bool {
-    //     check_path!(path);
-    //     self.re.is_match(&path[1..])
-    // }
-
-    pub fn path_to_parameters<P: param::FromParameters>(
-        &self,
-        path: &str,
-    ) -> Result<P, Cow<'static, str>> {
-        check_path!(path);
-
-        let ci = self.re.captures_iter(&path[1..]).next().unwrap();
-        let ps = self.params
+    /// Pairs the names of the parameters with their values in `path`, given the `captures` of a
+    /// `Match`.
+    pub fn captures<'a>(
+        &'a self,
+        path: &'a str,
+        captures: &[Option<(usize, usize)>],
+    ) -> Vec<(&'a str, Option<&'a str>)> {
+        debug_assert_eq!(self.params.len(), captures.len());
+        self.params
             .iter()
             .map(|s| s.as_str())
-            .zip(ci.iter().skip(1).map(|i| i.unwrap().as_str()));
-        // TODO: URL decode, POST body parsing
-        P::from_parameters(ps)
+            .zip(captures.iter().map(|c| c.map(|(start, end)| &path[start..end])))
+            .collect()
     }
 }
 
@@ -505,7 +1783,6 @@ fn test_pattern() {
     assert!(!pat1.is_match("/foo/bar/piyo/"));
     assert!(!pat1.is_match("/foo/bar/"));
     assert!(!pat1.is_match("/foo/bar"));
-    assert_eq!(pat1.to_re_string(), "(?-u:^foo/bar/([^/]+)$)");
     assert!(pat1.compile().params == vec!["user"]);
 
     let pat2: Pattern = "/foo/bar/{user?}".parse().expect("failed to parse");
@@ -513,21 +1790,18 @@ fn test_pattern() {
     assert!(!pat2.is_match("/foo/bar/piyo/"));
     assert!(pat2.is_match("/foo/bar/"));
     assert!(!pat2.is_match("/foo/bar"));
-    assert_eq!(pat2.to_re_string(), "(?-u:^foo/bar/([^/]*)$)");
 
     let pat3: Pattern = "/foo/bar/".parse().expect("failed to parse");
     assert!(!pat3.is_match("/foo/bar/piyo"));
     assert!(!pat3.is_match("/foo/bar/piyo/"));
     assert!(pat3.is_match("/foo/bar/"));
     assert!(!pat3.is_match("/foo/bar"));
-    assert_eq!(pat3.to_re_string(), "(?-u:^foo/bar/$)");
 
     let pat4: Pattern = "/foo/bar/?".parse().expect("failed to parse");
     assert!(!pat4.is_match("/foo/bar/piyo"));
     assert!(!pat4.is_match("/foo/bar/piyo/"));
     assert!(pat4.is_match("/foo/bar/"));
     assert!(pat4.is_match("/foo/bar"));
-    assert_eq!(pat4.to_re_string(), "(?-u:^foo/bar/?$)");
 
     let pat5: Pattern = "/foo/bar/:path".parse().expect("failed to parse");
     assert!(pat5.is_match("/foo/bar/piyo"));
@@ -535,7 +1809,6 @@ fn test_pattern() {
     assert!(pat5.is_match("/foo/bar/piyo/piyo"));
     assert!(pat5.is_match("/foo/bar/"));
     assert!(!pat5.is_match("/foo/bar"));
-    assert_eq!(pat5.to_re_string(), r"(?-u:^foo/bar/([^\s]*)$)");
 
     assert!(pat2 > pat1);
     assert!(pat3 > pat1);
@@ -563,14 +1836,427 @@ fn test_pattern() {
     assert_eq!(pset.matched_token("/foo/bar/piyo/"), Some(4));
 
     let p: Pattern = "/:path".parse().unwrap();
-    assert_eq!(p.to_re_string(), r"(?-u:^([^\s]*)$)");
     let p = PatternSet::from_iter(iter::once(p)).compile();
     assert!(p.is_match("/hugahuga"));
     assert!(p.is_match("/"));
 
     let p: Pattern = "/".parse().unwrap();
-    assert_eq!(p.to_re_string(), "(?-u:^$)");
+    assert_eq!(p.explain("/hugahuga"), Err(Mismatch::Segment {
+        index: 0,
+        expected: "".to_string(),
+        found: "hugahuga".to_string(),
+    }));
     let p = PatternSet::from_iter(iter::once(p)).compile();
     assert!(!p.is_match("/hugahuga"));
     assert!(p.is_match("/"));
 }
+
+#[test]
+fn test_constraints() {
+    let p: Pattern = "/users/{id:u8}".parse().unwrap();
+    assert_eq!(p.to_string(), "/users/{id:u8}");
+    assert!(p.is_match("/users/255"));
+    assert!(!p.is_match("/users/256"));
+    assert!(!p.is_match("/users/-1"));
+
+    let p: Pattern = "/posts/{slug:[a-z0-9-]+}".parse().unwrap();
+    assert_eq!(p.to_string(), "/posts/{slug:[a-z0-9-]+}");
+    assert!(p.is_match("/posts/hello-world"));
+    assert!(!p.is_match("/posts/Hello"));
+
+    let p: Pattern = "/{uuid:uuid}".parse().unwrap();
+    assert!(p.is_match("/67e55044-10b1-426f-9247-bb680e5fe0c8"));
+    assert!(!p.is_match("/67e55044"));
+
+    assert!("/{id?:u64}".parse::<Pattern>().is_err());
+    assert!("/{id:(a)}".parse::<Pattern>().is_err());
+    assert!("/{id:[a-}".parse::<Pattern>().is_err());
+
+    let any: Pattern = "/users/{id}".parse().unwrap();
+    let int: Pattern = "/users/{id:u64}".parse().unwrap();
+    let slug: Pattern = "/users/{id:[a-z]+}".parse().unwrap();
+    assert!(int < any);
+    assert!(slug < any);
+    assert!(int < slug);
+
+    let set = PatternSet::from_iter(vec![any, slug, int]).compile();
+    assert_eq!(set.matched_token("/users/42"), Some(2));
+    assert_eq!(set.matched_token("/users/99999999999999999999"), Some(0));
+    assert_eq!(set.matched_token("/users/abc"), Some(1));
+    let tokens = set.matches("/users/42").into_iter().map(|m| m.token);
+    assert_eq!(tokens.collect::<Vec<_>>(), vec![2, 0]);
+
+    // constraints apply to the decoded text
+    assert_eq!(set.matched_token("/users/%34%32"), Some(2));
+    let p: Pattern = "/tags/{tag:[a-z ]+}".parse().unwrap();
+    assert!(p.is_match("/tags/hello%20world"));
+    assert!(!p.is_match("/tags/hello%2Fworld"));
+    let set = PatternSet::from_iter(vec![p]).compile();
+    assert!(set.is_match("/tags/a%20b"));
+    let c: Constraint = "u8".parse().unwrap();
+    assert!(c.is_match("%31"));
+    assert!(!c.is_match("%2D1"));
+}
+
+#[test]
+fn test_mixed_segments() {
+    let p: Pattern = "/files/{name}.{ext}".parse().unwrap();
+    assert_eq!(p.to_string(), "/files/{name}.{ext}");
+    assert!(p.is_match("/files/a.txt"));
+    assert!(!p.is_match("/files/a"));
+    assert!(!p.is_match("/files/.txt"));
+    let set = PatternSet::from_iter(vec![p.clone()]).compile();
+    let m = set.matches("/files/a.tar.gz").pop().unwrap();
+    assert_eq!(
+        p.compile().captures("/files/a.tar.gz", &m.captures),
+        vec![("name", Some("a.tar")), ("ext", Some("gz"))]
+    );
+
+    let p: Pattern = "/v{version:u32}/items".parse().unwrap();
+    assert_eq!(p.to_string(), "/v{version:u32}/items");
+    assert_eq!(p.parameter_count(), 1);
+    assert!(p.is_match("/v2/items"));
+    assert!(!p.is_match("/vx/items"));
+    assert!(!p.is_match("/v99999999999/items"));
+
+    let p: Pattern = "/{year:[0-9]{4}}-{slug}".parse().unwrap();
+    assert_eq!(p.to_string(), "/{year:[0-9]{4}}-{slug}");
+    assert!(p.is_match("/2018-hello"));
+    assert!(!p.is_match("/18-hello"));
+
+    assert!("/{a}{b}".parse::<Pattern>().is_err());
+    assert!("/{a".parse::<Pattern>().is_err());
+    assert!("/a}".parse::<Pattern>().is_err());
+    assert!("/{}.txt".parse::<Pattern>().is_err());
+
+    let fixed: Pattern = "/files/a.txt".parse().unwrap();
+    let tar: Pattern = "/files/{name}.tar.gz".parse().unwrap();
+    let ext: Pattern = "/files/{name}.{ext}".parse().unwrap();
+    let txt: Pattern = "/files/{name}.txt".parse().unwrap();
+    let any: Pattern = "/files/{file}".parse().unwrap();
+    assert!(fixed < tar);
+    assert!(tar < txt);
+    assert!(txt < ext);
+    assert!(ext < any);
+
+    let set = PatternSet::from_iter(vec![any, ext, txt, tar, fixed]).compile();
+    assert_eq!(set.matched_token("/files/a.txt"), Some(4));
+    assert_eq!(set.matched_token("/files/b.txt"), Some(2));
+    assert_eq!(set.matched_token("/files/b.tar.gz"), Some(3));
+    assert_eq!(set.matched_token("/files/b.png"), Some(1));
+    assert_eq!(set.matched_token("/files/b"), Some(0));
+}
+
+#[test]
+fn test_errors() {
+    use self::PatternErrorKind::*;
+
+    let err = |s: &str| {
+        let e = s.parse::<Pattern>().unwrap_err();
+        (e.kind().clone(), e.offset().unwrap())
+    };
+    assert_eq!(err("/foo/:/bar"), (EmptyParameterName, 6));
+    assert_eq!(err("/foo//bar"), (EmptySegment, 5));
+    assert_eq!(err("/{id}/{id}"), (DuplicateParameter("id".to_string()), 6));
+    assert_eq!(err("/{id}/:id"), (DuplicateParameter("id".to_string()), 7));
+    assert_eq!(err("/{a}.{a}"), (DuplicateParameter("a".to_string()), 1));
+    assert_eq!(err("/a/{b"), (UnclosedBrace, 3));
+    assert_eq!(err("/a/b}"), (UnmatchedBrace, 4));
+    assert_eq!(err("/a/{b}{c}"), (AdjacentParameters, 6));
+    assert_eq!(err("/a/{}"), (EmptyParameterName, 4));
+    assert_eq!(err("/a/:"), (EmptyParameterName, 4));
+    assert_eq!(err("/a/{b:(c)}"), (InvalidConstraint("(c)".to_string()), 6));
+    assert_eq!(err("/a/{b?:u8}"), (OptionalConstraint, 4));
+    assert_eq!(err("/a b"), (IllegalCharacter(' '), 2));
+    assert_eq!(err("/a?b"), (IllegalCharacter('?'), 2));
+    assert_eq!(err("/a/%2"), (InvalidPercentEncoding, 3));
+
+    assert!("/a%20b/~user/@me/a:b".parse::<Pattern>().is_ok());
+    assert_eq!(
+        "/a/b c".parse::<Pattern>().unwrap_err().to_string(),
+        "illegal character ' ' in URL path at byte 4"
+    );
+}
+
+#[test]
+fn test_matches() {
+    let pats = [
+        "/",
+        "/?",
+        "/:path",
+        "/foo",
+        "/foo/",
+        "/foo/?",
+        "/foo/:rest",
+        "/foo/{a}",
+        "/foo/{a?}",
+        "/foo/{a:u8}",
+        "/foo/{a}/bar",
+        "/foo/{a}.{b}",
+        "/foo/x.{b}",
+        "/{a}/{b}/",
+        "/foo/:w/bar",
+        "/**/",
+    ].iter()
+        .map(|s| s.parse::<Pattern>().unwrap())
+        .collect::<Vec<_>>();
+    let pset = PatternSet::from_iter(pats.clone());
+    let set = pset.compile();
+
+    let paths = [
+        "/", "/foo", "/foo/", "/foo/1", "/foo/256", "/foo/x.y", "/foo/a.b.c", "/foo/1/bar",
+        "/foo/1/bar/", "/bar/baz/", "/%E3%81%82/", "/foo/a/b/bar", "/a/b/c/", "//",
+    ];
+    for path in &paths {
+        let expected = pset.iter()
+            .filter(|&(p, _)| p.is_match(path))
+            .map(|(_, &tok)| tok)
+            .collect::<Vec<_>>();
+        let found = set.matches(path);
+        assert_eq!(found.iter().map(|m| m.token).collect::<Vec<_>>(), expected, "{}", path);
+        assert_eq!(set.best(path).as_ref(), found.first(), "{}", path);
+        for m in found {
+            let cpat = pats[m.token].compile();
+            assert_eq!(cpat.params.len(), m.captures.len());
+        }
+    }
+
+    for path in &["", "*", "foo/1"] {
+        assert!(set.matches(path).is_empty());
+        assert!(set.find(path).is_none());
+        assert_eq!(pats[3].explain(path), Err(Mismatch::NotAbsolute));
+    }
+
+    let m = set.matches("/foo/a/b/").into_iter().next().unwrap();
+    assert_eq!(m.token, 6);
+    assert_eq!(pats[6].compile().captures("/foo/a/b/", &m.captures), vec![("rest", Some("a/b/"))]);
+    let m = set.matches("/foo/x.y").into_iter().next().unwrap();
+    assert_eq!(m.token, 12);
+    assert_eq!(m.captures, vec![Some((7, 8))]);
+}
+
+#[test]
+fn test_wildcards() {
+    let p: Pattern = "/repos/{owner}/**/blob/{file}".parse().unwrap();
+    assert_eq!(p.to_string(), "/repos/{owner}/**/blob/{file}");
+    assert_eq!(p.parameter_count(), 3);
+    assert!(p.is_match("/repos/me/a/b/blob/c"));
+    assert!(p.is_match("/repos/me/a/blob/c"));
+    assert_eq!(
+        p.explain("/repos/me/blob/c"),
+        Err(Mismatch::Segment {
+            index: 3,
+            expected: "blob".to_string(),
+            found: "c".to_string(),
+        })
+    );
+    assert_eq!(
+        p.explain("/repos/me/a/b/c"),
+        Err(Mismatch::Segment {
+            index: 3,
+            expected: "blob".to_string(),
+            found: "b".to_string(),
+        })
+    );
+
+    let p: Pattern = "/docs/:path/edit".parse().unwrap();
+    assert_eq!(p.to_string(), "/docs/:path/edit");
+    assert!(p.is_match("/docs/a/b/edit"));
+    assert_eq!(
+        p.explain("/docs//edit"),
+        Err(Mismatch::EmptyParameter {
+            index: 1,
+            name: "path".to_string(),
+        })
+    );
+    assert!("/docs/:path/:path".parse::<Pattern>().is_err());
+
+    let fixed: Pattern = "/docs/a/b/edit".parse().unwrap();
+    let param: Pattern = "/docs/{page}/edit".parse().unwrap();
+    let tail: Pattern = "/docs/:rest".parse().unwrap();
+    assert!(fixed < p);
+    assert!(param < p);
+    assert!(p < tail);
+
+    let set = PatternSet::from_iter(vec![p.clone(), fixed, param, tail]).compile();
+    assert_eq!(set.matched_token("/docs/a/b/edit"), Some(1));
+    assert_eq!(set.matched_token("/docs/a/edit"), Some(2));
+    assert_eq!(set.matched_token("/docs/a/c/edit"), Some(0));
+    assert_eq!(set.matched_token("/docs/a/c/edit/"), Some(3));
+    let m = set.matches("/docs/a/edit/edit").into_iter().next().unwrap();
+    assert_eq!(
+        p.compile().captures("/docs/a/edit/edit", &m.captures),
+        vec![("path", Some("a/edit"))]
+    );
+}
+
+#[test]
+fn test_case_insensitive() {
+    let pat = |s: &str, fold: bool| {
+        let mut p: Pattern = s.parse().unwrap();
+        p.set_case_insensitive(fold);
+        p
+    };
+    let exact = pat("/users/{id}", false);
+    let folded = pat("/Users/{id}", true);
+    let upper = pat("/USERS/{id}", true);
+    let other = pat("/Users/{id}", false);
+    assert!(folded.is_match("/uSeRs/MixedCase"));
+    assert!(!other.is_match("/users/1"));
+    assert!(exact < folded);
+    assert!(other < folded);
+    assert!(upper < folded);
+
+    let mixed = pat("/files/{name}.TXT", true);
+    assert!(mixed.is_match("/FILES/a.txt"));
+
+    let set = PatternSet::from_iter(vec![folded, exact, upper, mixed.clone()]).compile();
+    assert_eq!(set.matched_token("/users/1"), Some(1));
+    assert_eq!(set.matched_token("/Users/1"), Some(2));
+    assert_eq!(set.matched_token("/USERS/1"), Some(2));
+    let m = set.matches("/Files/ReadMe.Txt").pop().unwrap();
+    assert_eq!(m.token, 3);
+    assert_eq!(
+        mixed.compile().captures("/Files/ReadMe.Txt", &m.captures),
+        vec![("name", Some("ReadMe"))]
+    );
+}
+
+#[test]
+fn test_optional_groups() {
+    let p: Pattern = "/archive/{year}[/{month}[/{day}]]".parse().unwrap();
+    assert_eq!(p.to_string(), "/archive/{year}[/{month}[/{day}]]");
+    assert_eq!(p.parameter_count(), 3);
+    assert_eq!(
+        p.alternatives().iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+        vec![
+            "/archive/{year}/{month}/{day}",
+            "/archive/{year}/{month}",
+            "/archive/{year}",
+        ]
+    );
+    assert!(p.is_match("/archive/2017"));
+    assert!(p.is_match("/archive/2017/05"));
+    assert!(p.is_match("/archive/2017/05/01"));
+    assert!(!p.is_match("/archive"));
+    assert!(!p.is_match("/archive/2017/05/01/x"));
+
+    let posts: Pattern = "/posts".parse().unwrap();
+    let page: Pattern = "/posts/page/{n}".parse().unwrap();
+    let grouped: Pattern = "/posts[/page/{n}]".parse().unwrap();
+    assert_eq!(grouped.to_string(), "/posts[/page/{n}]");
+    assert!(posts < grouped);
+    assert!(page < grouped);
+
+    let err = |s: &str| {
+        let e = s.parse::<Pattern>().unwrap_err();
+        (e.kind, e.offset.unwrap())
+    };
+    assert_eq!(err("/posts[/page"), (PatternErrorKind::UnclosedGroup, 6));
+    assert_eq!(err("/posts/page]"), (PatternErrorKind::UnmatchedGroup, 11));
+    assert_eq!(err("/posts[page]"), (PatternErrorKind::InvalidGroup, 6));
+    assert_eq!(err("/posts[/page]x"), (PatternErrorKind::InvalidGroup, 13));
+    assert_eq!(err("/posts/[/page]"), (PatternErrorKind::EmptySegment, 7));
+    assert_eq!(err("/[/posts]"), (PatternErrorKind::EmptySegment, 1));
+    assert!("/posts[/{n}][/{n}]".parse::<Pattern>().is_err());
+
+    let set = PatternSet::from_iter(vec![grouped.clone(), posts, p.clone()]).compile();
+    assert_eq!(set.matched_token("/posts"), Some(1));
+    assert_eq!(set.matched_token("/posts/page/2"), Some(0));
+    assert_eq!(set.matched_token("/posts/page"), None);
+    let m = set.matches("/archive/2017/05").into_iter().next().unwrap();
+    assert_eq!(
+        p.compile().captures("/archive/2017/05", &m.captures),
+        vec![("year", Some("2017")), ("month", Some("05")), ("day", None)]
+    );
+}
+
+#[test]
+fn test_unicode_literals() {
+    let news: Pattern = "/ニュース/{id}".parse().unwrap();
+    assert_eq!(news.to_string(), "/ニュース/{id}");
+    assert!(news.is_match("/%E3%83%8B%E3%83%A5%E3%83%BC%E3%82%B9/1"));
+    assert!(news.is_match("/%e3%83%8b%e3%83%a5%e3%83%bc%e3%82%b9/1"));
+    let encoded: Pattern = "/%E3%83%8B%E3%83%A5%E3%83%BC%E3%82%B9/{id}".parse().unwrap();
+    let ext: Pattern = "/{name}.日本".parse().unwrap();
+    let cafe: Pattern = "/caf\u{e9}".parse().unwrap();
+    let menu: Pattern = "/{dish}@caf\u{e9}".parse().unwrap();
+
+    let pats = vec![news, encoded, ext, cafe, menu];
+    let set = PatternSet::from_iter(pats.clone()).compile();
+    let m = set.matches("/%E3%83%8B%E3%83%A5%E3%83%BC%E3%82%B9/1");
+    assert_eq!(m.len(), 2);
+    assert_eq!(set.matched_token("/a.%E6%97%A5%E6%9C%AC"), Some(2));
+    assert_eq!(set.matched_token("/a.%e6%97%a5%e6%9c%ac"), Some(2));
+    assert_eq!(set.find("/a.b.%e6%97%a5%e6%9c%ac").unwrap().get("name"), Some("a.b"));
+    assert!(!pats[2].is_match("/a.%E6%97%A5"));
+    assert_eq!(set.matched_token("/caf%C3%A9"), Some(3));
+    assert_eq!(set.matched_token("/cafe%CC%81"), None);
+    assert_eq!(set.matched_token("/tea@caf%c3%a9"), Some(4));
+    assert_eq!(set.matched_token("/tea@cafe%CC%81"), None);
+
+    let set = PatternSet::from_iter(pats.clone()).compile_with(true);
+    assert_eq!(set.matched_token("/caf%C3%A9"), Some(3));
+    assert_eq!(set.matched_token("/cafe%CC%81"), Some(3));
+    assert_eq!(set.matched_token("/cafe"), None);
+    assert_eq!(set.matched_token("/tea@cafe%CC%81"), Some(4));
+    assert_eq!(set.find("/tea@cafe%CC%81").unwrap().get("dish"), Some("tea"));
+    assert!(pats[4].explain_with("/tea@cafe%CC%81", true).is_ok());
+    assert!(pats[4].explain("/tea@cafe%CC%81").is_err());
+}
+
+#[test]
+fn test_find() {
+    let mut set = PatternSet::new();
+    let topic = set.insert("/orders/{id:u64}/:event".parse().unwrap()).unwrap();
+    let any = set.insert("/orders/**".parse().unwrap()).unwrap();
+    let archive = set.insert("/archive/{year}[/{month}]".parse().unwrap()).unwrap();
+    let set = set.compile();
+
+    let c = set.find("/orders/42/shipped").unwrap();
+    assert_eq!(c.token, topic);
+    assert_eq!(c.params, vec![("id", Some("42")), ("event", Some("shipped"))]);
+    assert_eq!(c.get("event"), Some("shipped"));
+    assert_eq!(c.get("unknown"), None);
+    assert_eq!(
+        set.find_all("/orders/42/shipped")
+            .iter()
+            .map(|c| c.token)
+            .collect::<Vec<_>>(),
+        vec![topic, any]
+    );
+    let c = set.find("/archive/2017").unwrap();
+    assert_eq!(c.token, archive);
+    assert_eq!(c.get("year"), Some("2017"));
+    assert_eq!(c.get("month"), None);
+    assert!(set.find("/users").is_none());
+}
+
+#[test]
+fn test_serialization() {
+    for s in &[
+        "/",
+        "/foo/",
+        "/foo/?",
+        "/foo/{bar?}/:rest",
+        "/files/{name}.{ext:[a-z]+}",
+        "/{id:u32}/{uuid:uuid}",
+        "/repos/**/blob/:path/edit",
+        "/archive/{year}[/{month}[/{day}]]",
+        "/ニュース/%20",
+        "(?i)/Users/{id}",
+    ] {
+        let p: Pattern = s.parse().unwrap();
+        assert_eq!(p.to_string(), *s);
+        assert_eq!(p.to_string().parse::<Pattern>().unwrap(), p);
+    }
+
+    let p: Pattern = "(?i)/foo".parse().unwrap();
+    let mut q: Pattern = "/foo".parse().unwrap();
+    assert!(p != q);
+    q.set_case_insensitive(true);
+    assert_eq!(p, q);
+
+    let e = "(?i)/a//b".parse::<Pattern>().unwrap_err();
+    assert_eq!((e.kind(), e.offset()), (&PatternErrorKind::EmptySegment, Some(7)));
+}
@@ -0,0 +1,330 @@
+//! Token-bucket rate limiting, attached to a `Router` or to a single route.
+
+use Ctx;
+use hyper::{Request, Response, StatusCode};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The state of a bucket after trying to take a token from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until the bucket is full again.
+    pub reset: Duration,
+    /// Time until a token is available. Zero if `allowed`.
+    pub retry_after: Duration,
+}
+
+/// Keeps the buckets of a `RateLimiter`.
+pub trait Store: 'static {
+    /// Takes a token from the bucket for `key`, which holds up to `capacity` tokens and refills
+    /// completely over `period`. Keys start with an id of the limiter, so that limiters sharing
+    /// a store keep separate buckets.
+    fn take(&self, key: &str, capacity: u32, period: Duration, now: Instant) -> Decision;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket is full again, and so no different from a new one.
+    full: Instant,
+    /// Position in `Buckets::lru`.
+    used: u64,
+}
+
+#[derive(Default)]
+struct Buckets {
+    map: HashMap<String, Bucket>,
+    /// Keys from the least to the most recently used.
+    lru: BTreeMap<u64, String>,
+    uses: u64,
+}
+
+/// Keeps buckets in memory, for up to `max_keys` clients.
+///
+/// When a new client arrives and the store is full, the least recently used bucket is dropped,
+/// along with the next ones as long as they have refilled.
+pub struct MemoryStore {
+    buckets: Mutex<Buckets>,
+    max_keys: usize,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore {
+            buckets: Default::default(),
+            max_keys: 100_000,
+        }
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Defaults to 100,000.
+    pub fn max_keys(mut self, max_keys: usize) -> Self {
+        assert!(max_keys > 0, "max_keys must be positive");
+        self.max_keys = max_keys;
+        self
+    }
+}
+
+impl Buckets {
+    fn evict(&mut self, now: Instant) {
+        let mut evicted = 0;
+        while let Some(used) = self.lru.keys().next().cloned() {
+            if evicted > 0 && self.map[&self.lru[&used]].full > now {
+                break;
+            }
+            let key = self.lru.remove(&used).unwrap();
+            self.map.remove(&key);
+            evicted += 1;
+        }
+    }
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+fn duration(secs: f64) -> Duration {
+    Duration::new(secs.trunc() as u64, (secs.fract() * 1e9) as u32)
+}
+
+impl Store for MemoryStore {
+    fn take(&self, key: &str, capacity: u32, period: Duration, now: Instant) -> Decision {
+        let capacity_f = f64::from(capacity);
+        let rate = capacity_f / secs(period);
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let buckets = &mut *buckets;
+        if buckets.map.len() >= self.max_keys && !buckets.map.contains_key(key) {
+            buckets.evict(now);
+        }
+        buckets.uses += 1;
+        let bucket = buckets.map.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity_f,
+            updated: now,
+            full: now,
+            used: 0,
+        });
+        let lru_key = buckets
+            .lru
+            .remove(&bucket.used)
+            .unwrap_or_else(|| key.to_string());
+        bucket.used = buckets.uses;
+        buckets.lru.insert(bucket.used, lru_key);
+        if now > bucket.updated {
+            let elapsed = secs(now.duration_since(bucket.updated));
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity_f);
+            bucket.updated = now;
+        }
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let reset = duration((capacity_f - bucket.tokens) / rate);
+        bucket.full = now + reset;
+        Decision {
+            allowed,
+            limit: capacity,
+            remaining: bucket.tokens.floor() as u32,
+            reset,
+            retry_after: if allowed {
+                Duration::from_secs(0)
+            } else {
+                duration((1.0 - bucket.tokens) / rate)
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Key {
+    ClientIp,
+    Header(Cow<'static, str>),
+    Custom(Arc<Fn(&Ctx<()>) -> Option<String>>),
+}
+
+/// Allows `capacity` requests per `period` for each client, with bursts of up to `capacity`.
+///
+/// Requests over the limit are answered with 429 Too Many Requests and `Retry-After`. All
+/// responses carry `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`.
+#[derive(Clone)]
+pub struct RateLimiter {
+    /// Prefixes the keys in `store`. Clones share it, along with the store.
+    id: usize,
+    capacity: u32,
+    period: Duration,
+    key: Key,
+    store: Arc<Store>,
+}
+
+impl RateLimiter {
+    /// Keys clients by IP address, using a `MemoryStore`.
+    pub fn new(capacity: u32, period: Duration) -> Self {
+        assert!(capacity > 0, "capacity must be positive");
+        assert!(period > Duration::from_secs(0), "period must be positive");
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        RateLimiter {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            capacity,
+            period,
+            key: Key::ClientIp,
+            store: Arc::new(MemoryStore::new()),
+        }
+    }
+
+    pub fn key_by_ip(mut self) -> Self {
+        self.key = Key::ClientIp;
+        self
+    }
+
+    pub fn key_by_header<N: Into<Cow<'static, str>>>(mut self, name: N) -> Self {
+        self.key = Key::Header(name.into());
+        self
+    }
+
+    /// Keys clients by the result of `f`. `params` of the `Ctx` are always empty.
+    pub fn key_by<F: Fn(&Ctx<()>) -> Option<String> + 'static>(mut self, f: F) -> Self {
+        self.key = Key::Custom(Arc::new(f));
+        self
+    }
+
+    pub fn store<S: Store>(mut self, store: S) -> Self {
+        self.store = Arc::new(store);
+        self
+    }
+
+    fn key(&self, ctx: &Ctx<()>) -> Option<String> {
+        match self.key {
+            Key::ClientIp => ctx.remote_addr().map(|addr| addr.ip().to_string()),
+            Key::Header(ref name) => header(ctx, name),
+            Key::Custom(ref f) => f(ctx),
+        }
+    }
+
+    /// Takes a token for the client of `ctx`. Requests without a key are not limited.
+    pub(crate) fn check(&self, ctx: &Ctx<()>) -> Option<Decision> {
+        let key = format!("{}:{}", self.id, self.key(ctx)?);
+        Some(
+            self.store
+                .take(&key, self.capacity, self.period, Instant::now()),
+        )
+    }
+}
+
+fn header(req: &Request, name: &str) -> Option<String> {
+    req.headers()
+        .get_raw(name)
+        .and_then(|raw| raw.one())
+        .map(|v| String::from_utf8_lossy(v).into_owned())
+}
+
+fn ceil_secs(d: Duration) -> u64 {
+    d.as_secs() + if d.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+pub(crate) fn set_headers(d: &Decision, res: &mut Response) {
+    let headers = res.headers_mut();
+    headers.set_raw("RateLimit-Limit", d.limit.to_string());
+    headers.set_raw("RateLimit-Remaining", d.remaining.to_string());
+    headers.set_raw("RateLimit-Reset", ceil_secs(d.reset).to_string());
+}
+
+pub(crate) fn rejection(d: &Decision) -> Response {
+    let mut res = Response::new()
+        .with_status(StatusCode::TooManyRequests)
+        .with_body("Too Many Requests");
+    res.headers_mut()
+        .set_raw("Retry-After", ceil_secs(d.retry_after).to_string());
+    set_headers(d, &mut res);
+    res
+}
+
+#[test]
+fn test_memory_store() {
+    let store = MemoryStore::new();
+    let period = Duration::from_secs(10);
+    let t0 = Instant::now();
+
+    let d = store.take("a", 2, period, t0);
+    assert!(d.allowed);
+    assert_eq!(d.remaining, 1);
+    assert!(store.take("a", 2, period, t0).allowed);
+
+    let d = store.take("a", 2, period, t0);
+    assert!(!d.allowed);
+    assert_eq!(d.remaining, 0);
+    assert_eq!(ceil_secs(d.retry_after), 5);
+    assert_eq!(ceil_secs(d.reset), 10);
+
+    assert!(store.take("b", 2, period, t0).allowed);
+    assert!(store.take("a", 2, period, t0 + Duration::from_secs(5)).allowed);
+    assert!(!store.take("a", 2, period, t0 + Duration::from_secs(5)).allowed);
+}
+
+#[test]
+fn test_memory_store_eviction() {
+    let store = MemoryStore::new().max_keys(2);
+    let period = Duration::from_secs(10);
+    let t0 = Instant::now();
+
+    assert!(store.take("a", 1, period, t0).allowed);
+    assert!(store.take("b", 1, period, t0 + Duration::from_secs(1)).allowed);
+    // "a" is the least recently used, so it makes room for "c"
+    assert!(store.take("c", 1, period, t0 + Duration::from_secs(2)).allowed);
+    assert_eq!(store.buckets.lock().unwrap().map.len(), 2);
+    assert!(!store.take("b", 1, period, t0 + Duration::from_secs(2)).allowed);
+    assert!(store.take("a", 1, period, t0 + Duration::from_secs(2)).allowed);
+
+    // by now every bucket has refilled
+    assert!(store.take("d", 1, period, t0 + Duration::from_secs(20)).allowed);
+    let buckets = store.buckets.lock().unwrap();
+    assert_eq!(buckets.map.len(), 1);
+    assert_eq!(buckets.lru.len(), 1);
+}
+
+#[test]
+fn test_shared_store() {
+    use futures::Future;
+    use hyper::Method;
+    use router::{Route, Router};
+
+    #[derive(Clone)]
+    struct Shared(Arc<MemoryStore>);
+    impl Store for Shared {
+        fn take(&self, key: &str, capacity: u32, period: Duration, now: Instant) -> Decision {
+            self.0.take(key, capacity, period, now)
+        }
+    }
+
+    let store = Shared(Arc::new(MemoryStore::new()));
+    let limiter = |store: &Shared| {
+        RateLimiter::new(1, Duration::from_secs(60))
+            .key_by(|_| Some("client".to_string()))
+            .store(store.clone())
+    };
+    let b = Router::new()
+        .route_with(Method::Get, "/a", "a", Route::new().rate_limit(limiter(&store)))
+        .route_with(Method::Get, "/b", "b", Route::new().rate_limit(limiter(&store)))
+        .compile();
+    let status = |path: &str| {
+        let req = Request::new(Method::Get, path.parse().unwrap());
+        let h = b.handler(&req).unwrap();
+        h(req, Default::default(), None).wait().unwrap().status()
+    };
+    assert_eq!(status("/a"), StatusCode::Ok);
+    // the limiter of "/b" doesn't share the bucket of "/a"
+    assert_eq!(status("/b"), StatusCode::Ok);
+    assert_eq!(status("/a"), StatusCode::TooManyRequests);
+    assert_eq!(store.0.buckets.lock().unwrap().map.len(), 2);
+}
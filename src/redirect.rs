@@ -0,0 +1,139 @@
+use {Ctx, Handler};
+use hyper::{self, Response, StatusCode};
+use hyper::header::Location;
+use std::collections::HashMap;
+
+/// Redirects to `target`, replacing `{name}` in it with the parameter of the same name captured by
+/// the route's pattern or the prefixes it's mounted under. Parameters are substituted as they
+/// appear in the request path, before percent-decoding, so that they can't inject `?`, `#` or
+/// header line breaks into `Location`.
+#[derive(Debug, Clone)]
+pub struct Redirect {
+    status: StatusCode,
+    target: String,
+}
+
+impl Redirect {
+    /// 301 Moved Permanently.
+    pub fn moved_permanently<T: Into<String>>(target: T) -> Self {
+        Self::with_status(StatusCode::MovedPermanently, target)
+    }
+
+    /// 302 Found.
+    pub fn found<T: Into<String>>(target: T) -> Self {
+        Self::with_status(StatusCode::Found, target)
+    }
+
+    /// 307 Temporary Redirect.
+    pub fn temporary<T: Into<String>>(target: T) -> Self {
+        Self::with_status(StatusCode::TemporaryRedirect, target)
+    }
+
+    /// 308 Permanent Redirect.
+    pub fn permanent<T: Into<String>>(target: T) -> Self {
+        Self::with_status(StatusCode::PermanentRedirect, target)
+    }
+
+    pub fn with_status<T: Into<String>>(status: StatusCode, target: T) -> Self {
+        assert!(status.is_redirection(), "{} is not a redirection", status);
+        Redirect {
+            status,
+            target: target.into(),
+        }
+    }
+
+    fn location(&self, params: &HashMap<&str, &str>) -> String {
+        let mut out = String::with_capacity(self.target.len());
+        let mut rest = &self.target[..];
+        while let Some(open) = rest.find('{') {
+            out.push_str(&rest[..open]);
+            rest = &rest[open..];
+            match rest.find('}').and_then(|close| params.get(&rest[1..close]).map(|v| (close, v))) {
+                Some((close, value)) => {
+                    escape(value, &mut out);
+                    rest = &rest[close + 1..];
+                }
+                None => {
+                    out.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        out.push_str(rest);
+        // `//host` would be a protocol-relative URL, redirecting to whatever host a parameter names
+        if out.starts_with("//") && !self.target.starts_with("//") {
+            out = format!("/%2F{}", &out[2..]);
+        }
+        out
+    }
+}
+
+impl Handler<HashMap<String, String>> for Redirect {
+    type Result = Result<Response, hyper::Error>;
+    type Error = hyper::Error;
+
+    fn call(&self, ctx: Ctx) -> Self::Result {
+        // Prefix parameters come first, so the route's own win when names clash.
+        let params = ctx.raw_params.iter().collect();
+        Ok(response(self.status, self.location(&params)))
+    }
+}
+
+/// Appends a raw path parameter to `out`, percent-encoding any byte that isn't allowed in a path
+/// segment. Existing escapes are kept as they are.
+fn escape(value: &str, out: &mut String) {
+    for &b in value.as_bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' => out.push(b as char),
+            b'-' | b'.' | b'_' | b'~' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+'
+            | b',' | b';' | b'=' | b':' | b'@' | b'/' | b'%' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+}
+
+pub(crate) fn response(status: StatusCode, location: String) -> Response {
+    Response::new()
+        .with_status(status)
+        .with_header(Location::new(location))
+}
+
+#[test]
+fn test_location() {
+    let mut params = HashMap::new();
+    params.insert("id", "42");
+    params.insert("path", "a/b");
+
+    let r = Redirect::found("/users/{id}/files/{path}");
+    assert_eq!(r.location(&params), "/users/42/files/a/b");
+    let r = Redirect::found("/{id}{id}/{unknown}/{");
+    assert_eq!(r.location(&params), "/4242/{unknown}/{");
+}
+
+#[test]
+fn test_location_escaping() {
+    let mut params = HashMap::new();
+    params.insert("id", "a%0D%0ASet-Cookie:%20x=1%3F");
+    params.insert("name", "caf\u{e9} ?#\r\n");
+
+    let r = Redirect::found("/new/{id}");
+    assert_eq!(r.location(&params), "/new/a%0D%0ASet-Cookie:%20x=1%3F");
+    let r = Redirect::found("/new/{name}");
+    assert_eq!(r.location(&params), "/new/caf%C3%A9%20%3F%23%0D%0A");
+}
+
+#[test]
+fn test_location_host() {
+    let mut params = HashMap::new();
+    params.insert("path", "/evil.com");
+    params.insert("empty", "");
+
+    assert_eq!(Redirect::found("/{path}").location(&params), "/%2Fevil.com");
+    assert_eq!(Redirect::found("{path}").location(&params), "/evil.com");
+    assert_eq!(Redirect::found("/{empty}/evil.com").location(&params), "/%2Fevil.com");
+    assert_eq!(Redirect::found("/a/{path}").location(&params), "/a//evil.com");
+    assert_eq!(
+        Redirect::found("//example.com/{path}").location(&params),
+        "//example.com//evil.com"
+    );
+}
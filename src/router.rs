@@ -1,27 +1,61 @@
-use {Ctx, Handler};
+use {Ctx, Data, Handler};
 use anymap::AnyMap;
-use futures::{Future, IntoFuture};
-use hyper::{Method, Request, Response};
-use param::FromParameters;
-use pattern::{CompiledPatternSet, Pattern, PatternSet};
+use anymap::any::{Any, IntoBox};
+use futures::{future, Future, IntoFuture};
+use futures::future::Either;
+use guard::Guard;
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use method_override::MethodOverride;
+use normalize::{self, Normalization};
+use openapi::{Document, Operation};
+use param::{Decoding, FromParameters, Params};
+use pattern::{CompiledPattern, CompiledPatternSet, Pattern, PatternSet};
+pub use pattern::{Mismatch, PatternError, PatternErrorKind};
+use rate_limit::{self, RateLimiter};
+use redirect;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use util::{Control, HttpMethodMap};
+use std::time::Duration;
+use tokio_timer::{self, Timer};
+use util::{Control, HttpMethodMap, STANDARD_METHODS};
 use vec_map::VecMap;
 
+/// Returns `$or` for paths that don't start with `/`, such as the `*` of `OPTIONS *`.
 macro_rules! check_path {
-    ($path:expr) => {
-        debug_assert!($path.starts_with('/'), "paths must start with '/'");
+    ($path:expr, $or:expr) => {
+        if !$path.starts_with('/') {
+            return $or;
+        }
     };
 }
 
+/// Takes the request, the data given to the server, and the address of the client.
 pub type RouteHandler = Arc<
-    Fn(Request, Arc<AnyMap>)
+    Fn(Request, Arc<AnyMap>, Option<SocketAddr>)
         -> Box<Future<Item = Response, Error = Box<Error + Send>>>,
 >;
 
 pub struct Router {
     routes: HttpMethodMap<PathRouter>,
+    /// Routes registered with `any`, kept to seed extension methods seen later.
+    any: PathRouter,
+    /// Fallbacks of this router and mounted routers, registered under `{prefix}/:path`.
+    fallback: PathRouter,
+    data: AnyMap,
+    on_timeout: Option<TimeoutHook>,
+    rate_limits: Vec<RateLimiter>,
+    redirect_trailing_slash: bool,
+    method_override: Option<MethodOverride>,
+    normalization: Option<Normalization>,
+    decoding: Decoding,
+    parameter_failure: ParameterFailure,
+    nfc: bool,
+    case_insensitive: bool,
     // err_routes: UncompiledPathRouter,
 }
 
@@ -29,70 +63,512 @@ impl Router {
     pub fn new() -> Self {
         Router {
             routes: HttpMethodMap::new(),
+            any: PathRouter::new(),
+            fallback: PathRouter::new(),
+            data: AnyMap::new(),
+            on_timeout: None,
+            rate_limits: Vec::new(),
+            redirect_trailing_slash: false,
+            method_override: None,
+            normalization: None,
+            decoding: Decoding::default(),
+            parameter_failure: ParameterFailure::BadRequest,
+            nfc: false,
+            case_insensitive: false,
             // err_routes: HttpMethodMap::new(),
         }
     }
 
     pub fn route<H: Handler<P> + 'static, P: FromParameters>(
-        mut self,
+        self,
         method: Method,
         pattern: &str,
         handler: H,
     ) -> Self {
-        let pattern: Pattern = pattern.parse().expect("failed to parse pattern");
-        let cpat = pattern.compile();
-        let f = move |req: Request,
-                      data: Arc<AnyMap>|
-              -> Box<Future<Item = Response, Error = Box<Error + Send>>> {
-            // println!("{:?} {:?} {:?}", cpat.re, cpat.params, req.path());
-            let params = cpat.path_to_parameters(req.path()).unwrap();
-
-            let fut = handler
-                .call(Ctx {
-                    params,
-                    data: data,
-                    request: req,
-                })
-                .into_future()
-                .map_err(|e| Box::new(e) as Box<Error + Send>);
-            Box::new(fut)
-        };
-        let f = Arc::new(f) as RouteHandler;
+        self.route_with(method, pattern, handler, Route::new())
+    }
 
-        if !self.routes.contains_key(&method) {
-            let mut pr = PathRouter::new();
-            pr.route(pattern, f);
-            self.routes.insert(method, pr);
-        } else {
-            self.routes
-                .get_mut(&method)
-                .expect("this must not happen")
-                .route(pattern, f);
+    /// Registers a route with additional options.
+    ///
+    /// Several routes may share a method and a pattern as long as they are distinguished by
+    /// guards. Candidates are tried in registration order, then less specific patterns are tried.
+    pub fn route_with<H: Handler<P> + 'static, P: FromParameters>(
+        self,
+        method: Method,
+        pattern: &str,
+        handler: H,
+        route: Route,
+    ) -> Self {
+        self.route_methods_with(&[method], pattern, handler, route)
+    }
+
+    /// Registers one handler for each of `methods`.
+    pub fn route_methods<H: Handler<P> + 'static, P: FromParameters>(
+        self,
+        methods: &[Method],
+        pattern: &str,
+        handler: H,
+    ) -> Self {
+        self.route_methods_with(methods, pattern, handler, Route::new())
+    }
+
+    pub fn route_methods_with<H: Handler<P> + 'static, P: FromParameters>(
+        self,
+        methods: &[Method],
+        pattern: &str,
+        handler: H,
+        route: Route,
+    ) -> Self {
+        let pattern = parse_route_pattern::<P>(pattern);
+        self.route_pattern(methods, pattern, handler, route)
+    }
+
+    /// Like `route`, but returns an error instead of panicking if `pattern` is invalid or has a
+    /// different number of parameters than the handler takes.
+    pub fn try_route<H: Handler<P> + 'static, P: FromParameters>(
+        self,
+        method: Method,
+        pattern: &str,
+        handler: H,
+    ) -> Result<Self, PatternError> {
+        self.try_route_methods_with(&[method], pattern, handler, Route::new())
+    }
+
+    /// Like `route_with`, but returns an error instead of panicking.
+    pub fn try_route_with<H: Handler<P> + 'static, P: FromParameters>(
+        self,
+        method: Method,
+        pattern: &str,
+        handler: H,
+        route: Route,
+    ) -> Result<Self, PatternError> {
+        self.try_route_methods_with(&[method], pattern, handler, route)
+    }
+
+    /// Like `route_methods`, but returns an error instead of panicking.
+    pub fn try_route_methods<H: Handler<P> + 'static, P: FromParameters>(
+        self,
+        methods: &[Method],
+        pattern: &str,
+        handler: H,
+    ) -> Result<Self, PatternError> {
+        self.try_route_methods_with(methods, pattern, handler, Route::new())
+    }
+
+    /// Like `route_methods_with`, but returns an error instead of panicking.
+    pub fn try_route_methods_with<H: Handler<P> + 'static, P: FromParameters>(
+        self,
+        methods: &[Method],
+        pattern: &str,
+        handler: H,
+        route: Route,
+    ) -> Result<Self, PatternError> {
+        let pattern = try_parse_route_pattern::<P>(pattern)?;
+        Ok(self.route_pattern(methods, pattern, handler, route))
+    }
+
+    fn route_pattern<H: Handler<P> + 'static, P: FromParameters>(
+        mut self,
+        methods: &[Method],
+        pattern: Pattern,
+        handler: H,
+        route: Route,
+    ) -> Self {
+        let mut pattern = pattern;
+        let fold = pattern.case_insensitive() || route.case_insensitive;
+        pattern.set_case_insensitive(fold);
+        let c = Candidate::new(handler, route);
+        for method in methods {
+            self.path_router(method).route(pattern.clone(), c.clone());
+        }
+        self
+    }
+
+    /// Registers one handler for all methods, including extension methods.
+    pub fn any<H: Handler<P> + 'static, P: FromParameters>(self, pattern: &str, handler: H) -> Self {
+        self.any_with(pattern, handler, Route::new())
+    }
+
+    pub fn any_with<H: Handler<P> + 'static, P: FromParameters>(
+        self,
+        pattern: &str,
+        handler: H,
+        route: Route,
+    ) -> Self {
+        let pattern = parse_route_pattern::<P>(pattern);
+        self.any_pattern(pattern, handler, route)
+    }
+
+    /// Like `any`, but returns an error instead of panicking.
+    pub fn try_any<H: Handler<P> + 'static, P: FromParameters>(
+        self,
+        pattern: &str,
+        handler: H,
+    ) -> Result<Self, PatternError> {
+        self.try_any_with(pattern, handler, Route::new())
+    }
+
+    /// Like `any_with`, but returns an error instead of panicking.
+    pub fn try_any_with<H: Handler<P> + 'static, P: FromParameters>(
+        self,
+        pattern: &str,
+        handler: H,
+        route: Route,
+    ) -> Result<Self, PatternError> {
+        let pattern = try_parse_route_pattern::<P>(pattern)?;
+        Ok(self.any_pattern(pattern, handler, route))
+    }
+
+    fn any_pattern<H: Handler<P> + 'static, P: FromParameters>(
+        mut self,
+        pattern: Pattern,
+        handler: H,
+        route: Route,
+    ) -> Self {
+        let mut pattern = pattern;
+        let fold = pattern.case_insensitive() || route.case_insensitive;
+        pattern.set_case_insensitive(fold);
+        let c = Candidate::new(handler, route);
+        for method in STANDARD_METHODS {
+            self.path_router(method).route(pattern.clone(), c.clone());
         }
+        self.routes.for_each_mut(|k, v| -> Control<()> {
+            if let Method::Extension(..) = *k {
+                v.route(pattern.clone(), c.clone());
+            }
+            Default::default()
+        });
+        self.any.route(pattern, c);
+        self
+    }
 
+    /// Sets the handler run for any method when no route matches. Once mounted, it only handles
+    /// paths under the mount prefix, in preference to the fallbacks of enclosing routers.
+    pub fn fallback<H: Handler<()> + 'static>(mut self, handler: H) -> Self {
+        let pattern: Pattern = "/:path".parse().expect("failed to parse pattern");
+        self.fallback.set(pattern, Candidate::fallback(handler));
         self
     }
 
-    pub fn mount(mut self, pattern: &str, b: Router) -> Self {
-        let pattern = pattern.parse().expect("failed to parse pattern");
-        b.routes.into_each(|k, v| -> Control<()> {
-            let new = v.0.prefix(&pattern);
-            // nll
-            if self.routes.contains_key(&k) {
-                self.routes.get_mut(&k).unwrap().merge(PathRouter(new, v.1));
+    /// If enabled, a request that doesn't match any route but would match with a trailing slash
+    /// added or removed is answered with 308 Permanent Redirect to that path. Only the setting of
+    /// the router being compiled takes effect.
+    pub fn redirect_trailing_slash(mut self, yes: bool) -> Self {
+        self.redirect_trailing_slash = yes;
+        self
+    }
+
+    /// Enables rewriting the method of POST requests before routing. Only the setting of the router
+    /// being compiled takes effect.
+    pub fn method_override(mut self, mo: MethodOverride) -> Self {
+        self.method_override = Some(mo);
+        self
+    }
+
+    /// Puts request paths in canonical form before routing, as described in `normalize`. Like
+    /// `redirect_trailing_slash`, only the setting of the router being compiled takes effect.
+    pub fn normalize_paths(mut self, normalization: Normalization) -> Self {
+        self.normalization = Some(normalization);
+        self
+    }
+
+    /// Sets how path parameters are percent-decoded. Like `redirect_trailing_slash`, only the
+    /// setting of the router being compiled takes effect.
+    pub fn decoding(mut self, decoding: Decoding) -> Self {
+        self.decoding = decoding;
+        self
+    }
+
+    /// Sets what happens when the parameters of a matching route can't be converted to the type
+    /// its handler takes. Like `redirect_trailing_slash`, only the setting of the router being
+    /// compiled takes effect.
+    pub fn on_parameter_failure(mut self, failure: ParameterFailure) -> Self {
+        self.parameter_failure = failure;
+        self
+    }
+
+    /// If enabled, fixed segments are compared with the path after NFC normalization of both, so
+    /// that `/café` matches however the client composed the `é`. Parameters are not normalized.
+    /// Like `redirect_trailing_slash`, only the setting of the router being compiled takes effect.
+    pub fn normalize_unicode(mut self, yes: bool) -> Self {
+        self.nfc = yes;
+        self
+    }
+
+    /// Makes all routes in this router, including mounted ones, match case-insensitively as
+    /// with `Route::case_insensitive`.
+    pub fn case_insensitive(mut self, yes: bool) -> Self {
+        self.case_insensitive = yes;
+        self
+    }
+
+    /// Sets a function called when a route in this router, including mounted ones, times out.
+    /// A hook set on a mounted router takes precedence for its routes.
+    pub fn on_timeout<F: Fn(&TimedOut) + 'static>(mut self, f: F) -> Self {
+        self.on_timeout = Some(Arc::new(f));
+        self
+    }
+
+    /// Limits requests to all routes in this router, including mounted ones. The limit is shared
+    /// among the routes.
+    pub fn rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limits.push(limiter);
+        self
+    }
+
+    /// Attaches a value visible to the handlers of all routes in this router, including mounted
+    /// ones.
+    pub fn data<T: IntoBox<Any>>(mut self, value: T) -> Self {
+        self.data.insert(value);
+        self
+    }
+
+    fn path_router(&mut self, method: &Method) -> &mut PathRouter {
+        if !self.routes.contains_key(method) {
+            let pr = if let Method::Extension(..) = *method {
+                self.any.clone()
             } else {
-                self.routes.insert(k.clone(), PathRouter(new, v.1));
+                PathRouter::new()
+            };
+            self.routes.insert(method.clone(), pr);
+        }
+        self.routes.get_mut(method).expect("this must not happen")
+    }
+
+    pub fn mount(self, pattern: &str, b: Router) -> Self {
+        self.try_mount(pattern, b)
+            .unwrap_or_else(|e| panic!("invalid pattern {:?}: {}", pattern, e))
+    }
+
+    /// Like `mount`, but returns an error instead of panicking if `pattern` is invalid.
+    pub fn try_mount(self, pattern: &str, b: Router) -> Result<Self, PatternError> {
+        let parsed: Pattern = pattern.parse()?;
+        if let Some(term) = parsed.terminator() {
+            let offset = pattern.rfind('/').map_or(0, |i| i + 1);
+            return Err(PatternError::new(
+                PatternErrorKind::Terminated(term.expr().into_owned()),
+                offset,
+            ));
+        }
+        Ok(self.mount_pattern(&parsed, b))
+    }
+
+    /// Merges the routes of `b` into this router, as if mounted at the root.
+    pub(crate) fn merge(self, b: Router) -> Self {
+        self.mount_pattern(&Pattern::new(), b)
+    }
+
+    fn mount_pattern(mut self, pattern: &Pattern, mut b: Router) -> Self {
+        b.close_scope();
+        let any = b.any.prefix(pattern);
+        let mut mounted = Vec::new();
+        b.routes.into_each(|k, v| -> Control<()> {
+            self.path_router(&k).merge(v.prefix(pattern));
+            mounted.push(k);
+            Default::default()
+        });
+        // extension methods unknown to `b` are still served by its `any` routes
+        self.routes.for_each_mut(|k, v| -> Control<()> {
+            if !mounted.contains(k) {
+                v.merge(any.clone());
             }
             Default::default()
         });
+        self.any.merge(any);
+        self.fallback.merge(b.fallback.prefix(pattern));
+        self
+    }
+
+    /// Returns a copy of this router to be mounted more than once.
+    pub(crate) fn duplicate(&mut self) -> Router {
+        self.close_scope();
+        Router {
+            routes: self.routes.clone(),
+            any: self.any.clone(),
+            fallback: self.fallback.clone(),
+            data: AnyMap::new(),
+            on_timeout: None,
+            rate_limits: Vec::new(),
+            redirect_trailing_slash: self.redirect_trailing_slash,
+            method_override: self.method_override.clone(),
+            normalization: self.normalization,
+            decoding: self.decoding,
+            parameter_failure: self.parameter_failure,
+            nfc: self.nfc,
+            case_insensitive: self.case_insensitive,
+        }
+    }
+
+    /// Adds `guard` to all routes registered so far, including fallbacks.
+    pub(crate) fn guard_all<G: Guard>(mut self, guard: G) -> Self {
+        let guard = Arc::new(guard) as Arc<Guard>;
+        self.for_each_candidate(|c| c.guards.push(Arc::clone(&guard)));
+        self
+    }
+
+    /// Applies `f` to the responses of all routes registered so far, including fallbacks, rate
+    /// limit rejections and timeouts.
+    pub(crate) fn map_responses<F: Fn(&mut Response) + 'static>(mut self, f: F) -> Self {
+        let f = Arc::new(f) as ResponseMap;
+        self.for_each_candidate(|c| c.response_maps.push(Arc::clone(&f)));
         self
     }
 
-    pub fn compile(self) -> CompiledRouter {
+    /// Pushes router-level data, the timeout hook, rate limits and case-insensitivity onto all
+    /// routes registered so far.
+    fn close_scope(&mut self) {
+        if self.case_insensitive {
+            self.routes.for_each_mut(|_, v| -> Control<()> {
+                v.fold_case();
+                Default::default()
+            });
+            self.any.fold_case();
+            self.fallback.fold_case();
+        }
+        let scope = if self.data.is_empty() {
+            None
+        } else {
+            Some(Arc::new(mem::replace(&mut self.data, AnyMap::new())))
+        };
+        let on_timeout = self.on_timeout.take();
+        let rate_limits = mem::replace(&mut self.rate_limits, Vec::new());
+        self.for_each_candidate(|c| {
+            if let Some(ref scope) = scope {
+                c.scopes.push(Arc::clone(scope));
+            }
+            if c.on_timeout.is_none() {
+                c.on_timeout = on_timeout.clone();
+            }
+            c.rate_limits.extend(rate_limits.iter().cloned());
+        });
+    }
+
+    fn for_each_candidate<F: FnMut(&mut Candidate)>(&mut self, mut f: F) {
+        self.routes.for_each_mut(|_, v| -> Control<()> {
+            v.for_each_candidate(&mut f);
+            Default::default()
+        });
+        self.any.for_each_candidate(&mut f);
+        self.fallback.for_each_candidate(&mut f);
+    }
+
+    /// Adds the routes registered so far to `doc`, in order of precedence. Fallbacks and routes
+    /// of extension methods are left out.
+    pub fn openapi(&self, mut doc: Document) -> Document {
+        let mut ops = Vec::new();
+        self.routes.for_each(|method, pr| -> Control<()> {
+            let name = match *method {
+                Method::Get => "get",
+                Method::Put => "put",
+                Method::Post => "post",
+                Method::Delete => "delete",
+                Method::Options => "options",
+                Method::Head => "head",
+                Method::Patch => "patch",
+                Method::Trace => "trace",
+                _ => return Default::default(),
+            };
+            for (pat, &tok) in pr.0.iter() {
+                // the first documented candidate describes the route
+                let doc = pr.1[tok].iter().filter_map(|c| c.doc.clone()).next();
+                ops.push((pat.clone(), name, doc));
+            }
+            Default::default()
+        });
+        // stable, so methods keep their order within a path
+        ops.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let undocumented = Operation::new();
+        for (pat, method, op) in ops {
+            doc.add_operation(method, &pat, op.as_ref().map_or(&undocumented, |op| &**op));
+        }
+        doc
+    }
+
+    pub fn compile(mut self) -> CompiledRouter {
+        self.close_scope();
+        let mut timeouts = Vec::new();
+        self.for_each_candidate(|c| timeouts.extend(c.timeout.map(|t| t.0)));
+        let timer = timer_for(&timeouts);
+        let timer = timer.as_ref();
+        let opts = CompileOptions {
+            decoding: self.decoding,
+            parameter_failure: self.parameter_failure,
+            nfc: self.nfc,
+        };
         CompiledRouter {
-            routes: self.routes.map(|_, value| value.compile()),
+            routes: self.routes.map(|_, value| value.compile(timer, opts)),
+            any: self.any.compile(timer, opts),
+            fallback: self.fallback.compile(timer, opts),
+            redirect_trailing_slash: self.redirect_trailing_slash,
+            method_override: self.method_override,
+            normalization: self.normalization,
+            nfc: self.nfc,
+        }
+    }
+}
+
+/// A timer wheel that can wait for the longest of `timeouts`, ticking often enough for the
+/// shortest to be accurate to about a tenth. `None` if there are no timeouts.
+fn timer_for(timeouts: &[Duration]) -> Option<Timer> {
+    let shortest = *timeouts.iter().min()?;
+    let longest = *timeouts.iter().max()?;
+    let tick = (shortest / 10)
+        .max(Duration::from_millis(1))
+        .min(Duration::from_millis(100));
+    Some(
+        tokio_timer::wheel()
+            .tick_duration(tick)
+            .max_timeout(longest)
+            .build(),
+    )
+}
+
+fn parse_pattern(pattern: &str) -> Pattern {
+    pattern
+        .parse()
+        .unwrap_or_else(|e| panic!("invalid pattern {:?}: {}", pattern, e))
+}
+
+/// Parses the pattern of a route whose handler takes `P`.
+fn parse_route_pattern<P: FromParameters>(pattern: &str) -> Pattern {
+    let pat = parse_pattern(pattern);
+    check_arity::<P>(&pat).unwrap_or_else(|e| panic!("invalid route {:?}: {}", pattern, e));
+    pat
+}
+
+fn try_parse_route_pattern<P: FromParameters>(pattern: &str) -> Result<Pattern, PatternError> {
+    let pat = pattern.parse()?;
+    check_arity::<P>(&pat)?;
+    Ok(pat)
+}
+
+fn check_arity<P: FromParameters>(pattern: &Pattern) -> Result<(), PatternError> {
+    match P::arity() {
+        Some(n) if n != pattern.parameter_count() => {
+            Err(PatternError::without_offset(PatternErrorKind::ParameterCount {
+                pattern: pattern.parameter_count(),
+                handler: n,
+            }))
         }
+        _ => Ok(()),
+    }
+}
+
+/// `uri` with its path replaced by `path`.
+fn with_path(uri: &Uri, path: &str) -> Uri {
+    let mut s = String::new();
+    if let (Some(scheme), Some(authority)) = (uri.scheme(), uri.authority()) {
+        s.push_str(scheme);
+        s.push_str("://");
+        s.push_str(authority);
     }
+    s.push_str(path);
+    if let Some(query) = uri.query() {
+        s.push('?');
+        s.push_str(query);
+    }
+    s.parse().expect("failed to rebuild URI")
 }
 
 impl Default for Router {
@@ -101,84 +577,920 @@ impl Default for Router {
     }
 }
 
+/// What happens when the parameters of a matching route can't be converted to the type its
+/// handler takes, as in `Ctx<(u32,)>` for `/items/abc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParameterFailure {
+    /// Respond with 400 Bad Request, with the error of `FromParameters` as the body.
+    BadRequest,
+    /// Try the next matching route, as if this one didn't match.
+    FallThrough,
+}
+
+/// Settings of the router being compiled that apply to all routes.
+#[derive(Clone, Copy)]
+struct CompileOptions {
+    decoding: Decoding,
+    parameter_failure: ParameterFailure,
+    nfc: bool,
+}
+
+/// Per-route options for `Router::route_with`.
+#[derive(Default)]
+pub struct Route {
+    guards: Vec<Arc<Guard>>,
+    data: AnyMap,
+    timeout: Option<(Duration, StatusCode)>,
+    rate_limits: Vec<RateLimiter>,
+    doc: Option<Operation>,
+    case_insensitive: bool,
+    name: Option<String>,
+}
+
+impl Route {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds a predicate the request must satisfy for this route to be chosen.
+    pub fn guard<G: Guard>(mut self, guard: G) -> Self {
+        self.guards.push(Arc::new(guard));
+        self
+    }
+
+    /// Attaches a value visible only to this route's handler.
+    pub fn data<T: IntoBox<Any>>(mut self, value: T) -> Self {
+        self.data.insert(value);
+        self
+    }
+
+    /// Drops the handler's future if it doesn't complete within `duration`, and responds with
+    /// 503 Service Unavailable instead.
+    pub fn timeout(self, duration: Duration) -> Self {
+        self.timeout_with_status(duration, StatusCode::ServiceUnavailable)
+    }
+
+    pub fn timeout_with_status(mut self, duration: Duration, status: StatusCode) -> Self {
+        self.timeout = Some((duration, status));
+        self
+    }
+
+    /// Limits requests to this route, in addition to the limits of enclosing routers.
+    pub fn rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limits.push(limiter);
+        self
+    }
+
+    /// Names this route, as seen by handlers in `MatchedRoute::name`.
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Documents this route in the output of `Router::openapi`.
+    pub fn doc(mut self, op: Operation) -> Self {
+        self.doc = Some(op);
+        self
+    }
+
+    /// If enabled, the literal text of the pattern, including mount prefixes, matches regardless
+    /// of ASCII case, as it does for patterns starting with `(?i)`. A case-sensitive route
+    /// precedes a case-insensitive one matching the same paths; among case-insensitive patterns
+    /// differing only in case, the first in byte order wins.
+    pub fn case_insensitive(mut self, yes: bool) -> Self {
+        self.case_insensitive = yes;
+        self
+    }
+}
+
+/// Describes the route that matched a request, for logging and metrics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchedRoute {
+    /// The route's pattern, including mount prefixes, such as `/api/users/{id}`. Doesn't have
+    /// the `(?i)` prefix of case-insensitive patterns.
+    pub pattern: String,
+    /// The name set with `Route::name`.
+    pub name: Option<String>,
+    /// The mount prefixes of the route, such as `/api`. Empty if the route wasn't mounted.
+    pub prefix: String,
+    /// Whether the pattern matches regardless of ASCII case.
+    pub case_insensitive: bool,
+}
+
+/// Describes a request whose handler didn't complete in time.
+#[derive(Debug)]
+pub struct TimedOut {
+    pub method: Method,
+    /// The route's pattern, including mount prefixes, as in `MatchedRoute`.
+    pub pattern: String,
+    pub path: String,
+    pub timeout: Duration,
+}
+
+type TimeoutHook = Arc<Fn(&TimedOut)>;
+
+type ResponseMap = Arc<Fn(&mut Response)>;
+
 pub struct CompiledRouter {
     routes: HttpMethodMap<CompiledPathRouter>,
+    any: CompiledPathRouter,
+    fallback: CompiledPathRouter,
+    redirect_trailing_slash: bool,
+    method_override: Option<MethodOverride>,
+    normalization: Option<Normalization>,
+    nfc: bool,
 }
 
 impl CompiledRouter {
+    /// Whether a route matches `path`, after rewriting it if paths are normalized that way.
     #[inline]
     pub fn is_match(&self, method: &Method, path: &str) -> bool {
-        check_path!(path);
+        check_path!(path, false);
 
-        if let Some(pr) = self.routes.get(method) {
-            pr.0.is_match(path)
-        } else {
-            false
+        match self.normalize(path) {
+            Some((Normalization::Rewrite, canonical)) => {
+                self.path_router(method).0.is_match(&canonical)
+            }
+            _ => self.path_router(method).0.is_match(path),
         }
     }
 
     #[inline]
-    pub fn handler(&self, method: &Method, path: &str) -> Option<RouteHandler> {
-        check_path!(path);
+    pub fn handler(&self, req: &Request) -> Option<RouteHandler> {
+        check_path!(req.path(), None);
 
-        if let Some(pr) = self.routes.get(method) {
-            pr.handler(path)
-        } else {
-            None
+        match self.normalize(req.path()) {
+            None => self.lookup(req, req.path()),
+            Some((Normalization::Rewrite, canonical)) => {
+                let handler = self.lookup(req, &canonical)?;
+                let uri = with_path(req.uri(), &canonical);
+                Some(Arc::new(move |mut req: Request, data, remote_addr| {
+                    req.set_uri(uri.clone());
+                    handler(req, data, remote_addr)
+                }))
+            }
+            Some((Normalization::Redirect, canonical)) => {
+                let mut location = canonical;
+                if let Some(query) = req.query() {
+                    location.push('?');
+                    location.push_str(query);
+                }
+                Some(Arc::new(move |_, _, _| {
+                    let res = redirect::response(StatusCode::PermanentRedirect, location.clone());
+                    Box::new(future::ok(res))
+                }))
+            }
         }
     }
-}
 
-impl From<Router> for CompiledRouter {
-    fn from(r: Router) -> Self {
-        r.compile()
+    /// Looks up the handler for `req` as if it had been made to `path`.
+    fn lookup(&self, req: &Request, path: &str) -> Option<RouteHandler> {
+        self.path_router(req.method())
+            .handler(req, path)
+            .or_else(|| self.trailing_slash_redirect(req, path))
+            .or_else(|| self.fallback.handler(req, path))
     }
-}
 
-struct PathRouter(PatternSet, VecMap<RouteHandler>);
+    /// The canonical form of `path` if normalization is enabled and `path` isn't canonical.
+    fn normalize(&self, path: &str) -> Option<(Normalization, String)> {
+        let normalization = self.normalization?;
+        normalize::canonical_path(path).map(|canonical| (normalization, canonical))
+    }
 
-impl PathRouter {
-    fn new() -> Self {
-        PathRouter(PatternSet::new(), VecMap::new())
+    /// Lists the patterns tried for a request to `path` without headers, and how each one fared.
+    /// `path` may have a query.
+    pub fn explain(&self, method: Method, path: &str) -> Result<Explanation, InvalidPath> {
+        let uri = match path.parse::<Uri>() {
+            Ok(ref uri) if uri.path().starts_with('/') => uri.clone(),
+            _ => return Err(InvalidPath(path.to_string())),
+        };
+        Ok(self.explain_request(&Request::new(method, uri)))
     }
 
-    fn route(&mut self, pattern: Pattern, handler: RouteHandler) -> &mut Self {
-        let n = self.1.len();
-        assert_eq!(self.0.insert(pattern), Some(n));
-        self.1.insert(n, handler);
-        self
+    /// Like `explain`, but guards see the headers of `req`. Nothing is tried for paths that don't
+    /// start with `/`.
+    pub fn explain_request(&self, req: &Request) -> Explanation {
+        check_path!(
+            req.path(),
+            Explanation {
+                candidates: vec![],
+                winner: None,
+                trailing_slash_redirect: false,
+                normalized: None,
+            }
+        );
+
+        let normalized = self.normalize(req.path());
+        let path = normalized.as_ref().map_or(req.path(), |&(_, ref p)| p.as_str());
+        let redirected = matches!(normalized, Some((Normalization::Redirect, _)));
+
+        let mut patterns = BTreeMap::<Pattern, Vec<Method>>::new();
+        self.routes.for_each(|method, pr| -> Control<()> {
+            for (pat, _) in pr.2.iter() {
+                patterns
+                    .entry(pat.clone())
+                    .or_insert_with(Vec::new)
+                    .push(method.clone());
+            }
+            Default::default()
+        });
+        let own = self.path_router(req.method());
+        for (pat, _) in own.2.iter() {
+            let methods = patterns.entry(pat.clone()).or_insert_with(Vec::new);
+            if !methods.contains(req.method()) {
+                methods.push(req.method().clone());
+            }
+        }
+
+        let mut candidates = patterns
+            .into_iter()
+            .map(|(pat, methods)| {
+                let outcome = match pat.explain_with(path, self.nfc) {
+                    Err(m) => Outcome::Mismatch(m),
+                    Ok(()) => own.accepts(&pat, req, path)
+                        .unwrap_or(Outcome::WrongMethod(methods)),
+                };
+                Report {
+                    pattern: pat.to_string(),
+                    fallback: false,
+                    outcome,
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut winner = candidates
+            .iter()
+            .position(|c| c.outcome == Outcome::Matched);
+        let trailing_slash_redirect =
+            !redirected && winner.is_none() && self.trailing_slash_redirect(req, path).is_some();
+
+        for (pat, _) in self.fallback.2.iter() {
+            let outcome = match pat.explain_with(path, self.nfc) {
+                Err(m) => Outcome::Mismatch(m),
+                Ok(()) => self.fallback
+                    .accepts(pat, req, path)
+                    .unwrap_or(Outcome::Matched),
+            };
+            if winner.is_none() && !trailing_slash_redirect && outcome == Outcome::Matched {
+                winner = Some(candidates.len());
+            }
+            candidates.push(Report {
+                pattern: pat.to_string(),
+                fallback: true,
+                outcome,
+            });
+        }
+        if redirected {
+            winner = None;
+        }
+
+        Explanation {
+            candidates,
+            winner,
+            trailing_slash_redirect,
+            normalized,
+        }
     }
 
-    fn compile(self) -> CompiledPathRouter {
-        CompiledPathRouter(self.0.compile(), self.1)
+    /// The step to run on requests before looking up their handlers, if any.
+    #[inline]
+    pub fn method_override(&self) -> Option<&MethodOverride> {
+        self.method_override.as_ref()
     }
 
-    fn merge(&mut self, other: PathRouter) {
-        self.0.merge(&other.0);
-        let ofs = self.1.len();
-        self.1
-            .extend(other.1.into_iter().map(|(k, v)| (k + ofs, v)));
+    fn trailing_slash_redirect(&self, req: &Request, path: &str) -> Option<RouteHandler> {
+        if !self.redirect_trailing_slash || path == "/" {
+            return None;
+        }
+
+        let mut location = if path.ends_with('/') {
+            path[..path.len() - 1].to_string()
+        } else {
+            format!("{}/", path)
+        };
+        if !self.path_router(req.method()).0.is_match(&location) {
+            return None;
+        }
+        if let Some(query) = req.query() {
+            location.push('?');
+            location.push_str(query);
+        }
+
+        Some(Arc::new(move |_, _, _| {
+            let res = redirect::response(StatusCode::PermanentRedirect, location.clone());
+            Box::new(future::ok(res))
+        }))
+    }
+
+    #[inline]
+    fn path_router(&self, method: &Method) -> &CompiledPathRouter {
+        // standard methods are always present once `any` has been used
+        self.routes.get(method).unwrap_or(&self.any)
     }
 }
 
-struct CompiledPathRouter(CompiledPatternSet, VecMap<RouteHandler>);
+/// A path given to `CompiledRouter::explain` that isn't a valid request target starting with `/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidPath(pub String);
 
-impl CompiledPathRouter {
-    #[inline]
-    fn handler(&self, path: &str) -> Option<RouteHandler> {
-        self.0
-            .matched_token(path)
-            .map(|tok| Arc::clone(&self.1[tok]))
+impl fmt::Display for InvalidPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid path {:?}", self.0)
     }
 }
 
-#[test]
-fn test_router() {
-    use std::io;
+impl Error for InvalidPath {
+    fn description(&self) -> &str {
+        "invalid path"
+    }
+}
 
-    let b = Router::new()
-        .route(
-            Method::Get,
+/// The result of `CompiledRouter::explain`. Its `Display` output is a table meant for humans.
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    /// Every pattern, in the order they are tried, fallbacks last.
+    pub candidates: Vec<Report>,
+    /// Index of the candidate handling the request.
+    pub winner: Option<usize>,
+    /// Whether the request is redirected to add or remove a trailing slash, in which case there
+    /// is no winner.
+    pub trailing_slash_redirect: bool,
+    /// The canonical path, if normalization changed it. Patterns are tried against it, and with
+    /// `Normalization::Redirect` the request is redirected there, so there is no winner.
+    pub normalized: Option<(Normalization, String)>,
+}
+
+/// How a pattern fared in `CompiledRouter::explain`.
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// The full pattern, including mount prefixes.
+    pub pattern: String,
+    /// Whether the pattern belongs to a fallback.
+    pub fallback: bool,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The pattern matched and one of its routes would handle the request, unless a candidate
+    /// tried earlier does.
+    Matched,
+    Mismatch(Mismatch),
+    /// The path matched, but the pattern has no routes for the method. Holds the methods it has.
+    WrongMethod(Vec<Method>),
+    /// The path matched, but the guards of all of its routes, whose number this holds, rejected
+    /// the request.
+    Guarded(usize),
+    /// The path matched, but with `ParameterFailure::FallThrough`, its parameters didn't convert
+    /// for any of the routes whose guards accepted the request. Holds the number of those routes.
+    Unconvertible(usize),
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self.candidates
+            .iter()
+            .map(|c| c.pattern.len())
+            .max()
+            .unwrap_or(0);
+        for (i, c) in self.candidates.iter().enumerate() {
+            let mark = if self.winner == Some(i) { "=>" } else { "  " };
+            write!(f, "{} {:width$}  ", mark, c.pattern, width = width)?;
+            match c.outcome {
+                Outcome::Matched if self.winner == Some(i) => write!(f, "matched")?,
+                Outcome::Matched => write!(f, "matched, but not tried")?,
+                Outcome::Mismatch(ref m) => write!(f, "{}", m)?,
+                Outcome::WrongMethod(ref methods) => {
+                    write!(f, "method not allowed (")?;
+                    for (i, m) in methods.iter().enumerate() {
+                        write!(f, "{}{}", if i == 0 { "" } else { ", " }, m)?;
+                    }
+                    write!(f, ")")?;
+                }
+                Outcome::Guarded(n) => write!(f, "rejected by guards of {} route(s)", n)?,
+                Outcome::Unconvertible(n) => {
+                    write!(f, "parameters don't convert for {} route(s)", n)?
+                }
+            }
+            if c.fallback {
+                write!(f, " [fallback]")?;
+            }
+            writeln!(f)?;
+        }
+        if self.trailing_slash_redirect {
+            writeln!(f, "=> redirected to toggle the trailing slash")?;
+        }
+        match self.normalized {
+            Some((Normalization::Rewrite, ref path)) => writeln!(f, "(rewritten to {})", path)?,
+            Some((Normalization::Redirect, ref path)) => writeln!(f, "=> redirected to {}", path)?,
+            None => (),
+        }
+        Ok(())
+    }
+}
+
+impl From<Router> for CompiledRouter {
+    fn from(r: Router) -> Self {
+        r.compile()
+    }
+}
+
+/// Takes the byte ranges of the parameters in the path, as found by the matcher.
+type RawHandler = Arc<
+    Fn(Request, Data, &RouteInfo, &[Option<(usize, usize)>])
+        -> Box<Future<Item = Response, Error = Box<Error + Send>>>,
+>;
+
+/// A `RouteHandler` before it's given the parameters found by the matcher.
+type MatchedHandler = Arc<
+    Fn(Request, Arc<AnyMap>, Option<SocketAddr>, &[Option<(usize, usize)>])
+        -> Box<Future<Item = Response, Error = Box<Error + Send>>>,
+>;
+
+/// Tests if the parameters in the path convert to the type the handler takes.
+type ParameterCheck = Arc<Fn(&RouteInfo, &str, &[Option<(usize, usize)>]) -> bool>;
+
+/// A `ParameterCheck` given the route.
+type MatchCheck = Arc<Fn(&str, &[Option<(usize, usize)>]) -> bool>;
+
+/// What a compiled candidate knows about the route it belongs to.
+#[derive(Clone)]
+struct RouteInfo {
+    /// Compiled from the full pattern, including prefixes added by `Router::mount`.
+    cpat: Arc<CompiledPattern>,
+    route: Arc<MatchedRoute>,
+    prefix_params: usize,
+    decoding: Decoding,
+}
+
+/// The parameters of a request.
+struct Captured {
+    prefix: Params,
+    /// The route's own parameters, decoded, or `None` if in an absent optional group.
+    own: Vec<(String, Option<String>)>,
+    raw: Params,
+}
+
+impl Captured {
+    fn own<'a>(&'a self) -> Box<Iterator<Item = (&'a str, Option<&'a str>)> + 'a> {
+        Box::new(
+            self.own
+                .iter()
+                .map(|&(ref n, ref v)| (n.as_str(), v.as_ref().map(|v| v.as_str()))),
+        )
+    }
+}
+
+impl RouteInfo {
+    /// Extracts and decodes the parameters in `path`, or returns 400 Bad Request if the decoding
+    /// policy rejects one.
+    fn captures(&self, path: &str, ranges: &[Option<(usize, usize)>]) -> Result<Captured, Response> {
+        let captures = self.cpat.captures(path, ranges);
+        let mut decoded = Vec::with_capacity(captures.len());
+        for &(n, v) in &captures {
+            let v = match v {
+                Some(v) => Some(self.decoding.decode(v).map_err(bad_request)?),
+                None => None,
+            };
+            decoded.push((n.to_string(), v.map(|v| v.into_owned())));
+        }
+        let own = decoded.split_off(self.prefix_params);
+        let prefix = decoded
+            .into_iter()
+            .filter_map(|(n, v)| v.map(|v| (n, v)))
+            .collect();
+        let raw = captures
+            .into_iter()
+            .filter_map(|(n, v)| v.map(|v| (n.to_string(), v.to_string())))
+            .collect();
+        Ok(Captured {
+            prefix: Params::new(prefix),
+            own,
+            raw: Params::new(raw),
+        })
+    }
+}
+
+#[derive(Clone)]
+struct Candidate {
+    guards: Vec<Arc<Guard>>,
+    handler: RawHandler,
+    /// Data scopes, innermost first.
+    scopes: Vec<Arc<AnyMap>>,
+    /// Number of leading parameters captured by mount prefixes.
+    prefix_params: usize,
+    /// The mount prefixes, outermost first.
+    prefix: String,
+    name: Option<String>,
+    timeout: Option<(Duration, StatusCode)>,
+    on_timeout: Option<TimeoutHook>,
+    /// Route limits first, followed by those of enclosing routers.
+    rate_limits: Vec<RateLimiter>,
+    doc: Option<Arc<Operation>>,
+    /// `None` for fallbacks, which take no parameters.
+    check: Option<ParameterCheck>,
+    /// Applied in order to every response, after rate limiting and timeouts.
+    response_maps: Vec<ResponseMap>,
+}
+
+impl Candidate {
+    fn new<H: Handler<P> + 'static, P: FromParameters>(handler: H, route: Route) -> Self {
+        let f = move |req: Request,
+                      data: Data,
+                      info: &RouteInfo,
+                      ranges: &[Option<(usize, usize)>]|
+              -> Box<Future<Item = Response, Error = Box<Error + Send>>> {
+            let captured = match info.captures(req.path(), ranges) {
+                Ok(c) => c,
+                Err(res) => return Box::new(future::ok(res)),
+            };
+            let params = match P::from_optional_parameters(captured.own()) {
+                Ok(params) => params,
+                Err(e) => return Box::new(future::ok(bad_request(e))),
+            };
+            call(&handler, params, info, captured, data, req)
+        };
+        let check = |info: &RouteInfo, path: &str, ranges: &[Option<(usize, usize)>]| {
+            info.captures(path, ranges)
+                .ok()
+                .map_or(false, |c| P::from_optional_parameters(c.own()).is_ok())
+        };
+        let scopes = if route.data.is_empty() {
+            vec![]
+        } else {
+            vec![Arc::new(route.data)]
+        };
+        Candidate {
+            guards: route.guards,
+            handler: Arc::new(f) as RawHandler,
+            scopes,
+            prefix_params: 0,
+            prefix: String::new(),
+            name: route.name,
+            timeout: route.timeout,
+            on_timeout: None,
+            rate_limits: route.rate_limits,
+            doc: route.doc.map(Arc::new),
+            check: Some(Arc::new(check)),
+            response_maps: vec![],
+        }
+    }
+
+    /// A candidate whose handler takes no parameters besides the prefix ones.
+    fn fallback<H: Handler<()> + 'static>(handler: H) -> Self {
+        let f = move |req: Request,
+                      data: Data,
+                      info: &RouteInfo,
+                      ranges: &[Option<(usize, usize)>]|
+              -> Box<Future<Item = Response, Error = Box<Error + Send>>> {
+            match info.captures(req.path(), ranges) {
+                Ok(captured) => call(&handler, (), info, captured, data, req),
+                Err(res) => Box::new(future::ok(res)),
+            }
+        };
+        Candidate {
+            guards: vec![],
+            handler: Arc::new(f) as RawHandler,
+            scopes: vec![],
+            prefix_params: 0,
+            prefix: String::new(),
+            name: None,
+            timeout: None,
+            on_timeout: None,
+            rate_limits: vec![],
+            doc: None,
+            check: None,
+            response_maps: vec![],
+        }
+    }
+
+    fn compile(
+        self,
+        pattern: &Pattern,
+        cpat: &Arc<CompiledPattern>,
+        timer: Option<&Timer>,
+        opts: CompileOptions,
+    ) -> CompiledCandidate {
+        let Candidate {
+            guards,
+            handler,
+            scopes,
+            prefix_params,
+            prefix,
+            name,
+            timeout,
+            on_timeout,
+            rate_limits,
+            check,
+            response_maps,
+            ..
+        } = self;
+        let scopes = Arc::new(scopes);
+        let info = RouteInfo {
+            cpat: Arc::clone(cpat),
+            route: Arc::new(MatchedRoute {
+                pattern: pattern.template(),
+                name,
+                prefix,
+                case_insensitive: pattern.case_insensitive(),
+            }),
+            prefix_params,
+            decoding: opts.decoding,
+        };
+        let check = match check {
+            Some(check) if opts.parameter_failure == ParameterFailure::FallThrough => {
+                let info = info.clone();
+                Some(Arc::new(move |path: &str, ranges: &[Option<(usize, usize)>]| {
+                    check(&info, path, ranges)
+                }) as MatchCheck)
+            }
+            _ => None,
+        };
+        let f = move |req: Request,
+                      global: Arc<AnyMap>,
+                      remote_addr: Option<SocketAddr>,
+                      ranges: &[Option<(usize, usize)>]|
+              -> Box<Future<Item = Response, Error = Box<Error + Send>>> {
+            let data = Data::new(Arc::clone(&scopes), global, remote_addr);
+            if rate_limits.is_empty() {
+                return handler(req, data, &info, ranges);
+            }
+
+            let captured = match info.captures(req.path(), ranges) {
+                Ok(c) => c,
+                Err(res) => return Box::new(future::ok(res)),
+            };
+            let ctx = Ctx {
+                params: (),
+                route: Arc::clone(&info.route),
+                prefix_params: captured.prefix,
+                raw_params: captured.raw,
+                data,
+                request: req,
+            };
+            // Route limiters come first. Stopping at the first rejection keeps a request that
+            // one of them rejects from using up the tokens of the others.
+            let mut decisions = Vec::with_capacity(rate_limits.len());
+            for d in rate_limits.iter().filter_map(|l| l.check(&ctx)) {
+                if !d.allowed {
+                    return Box::new(future::ok(rate_limit::rejection(&d)));
+                }
+                decisions.push(d);
+            }
+
+            let fut = handler(ctx.request, ctx.data, &info, ranges);
+            match decisions.into_iter().min_by_key(|d| d.remaining) {
+                Some(d) => Box::new(fut.map(move |mut res| {
+                    rate_limit::set_headers(&d, &mut res);
+                    res
+                })),
+                None => fut,
+            }
+        };
+
+        let handler: MatchedHandler = match timeout {
+            Some((duration, status)) => {
+                let timer = timer.expect("timer must be created for routes with timeout").clone();
+                let pattern = pattern.template();
+                Arc::new(move |req: Request,
+                              global: Arc<AnyMap>,
+                              remote_addr: Option<SocketAddr>,
+                              ranges: &[Option<(usize, usize)>]| {
+                    let method = req.method().clone();
+                    let path = req.path().to_string();
+                    let pattern = pattern.clone();
+                    let on_timeout = on_timeout.clone();
+                    let fut = f(req, global, remote_addr, ranges)
+                        .select2(timer.sleep(duration))
+                        .then(move |r| match r {
+                            Ok(Either::A((res, _))) => Ok(res),
+                            Err(Either::A((e, _))) => Err(e),
+                            Ok(Either::B(((), _))) => {
+                                if let Some(on_timeout) = on_timeout {
+                                    on_timeout(&TimedOut {
+                                        method,
+                                        pattern,
+                                        path,
+                                        timeout: duration,
+                                    });
+                                }
+                                Ok(Response::new().with_status(status))
+                            }
+                            Err(Either::B((e, _))) => Err(Box::new(e) as Box<Error + Send>),
+                        });
+                    Box::new(fut) as Box<Future<Item = Response, Error = Box<Error + Send>>>
+                })
+            }
+            None => Arc::new(f),
+        };
+        let handler = if response_maps.is_empty() {
+            handler
+        } else {
+            let response_maps = Arc::new(response_maps);
+            Arc::new(move |req: Request,
+                           global: Arc<AnyMap>,
+                           remote_addr: Option<SocketAddr>,
+                           ranges: &[Option<(usize, usize)>]| {
+                let maps = Arc::clone(&response_maps);
+                Box::new(handler(req, global, remote_addr, ranges).map(move |mut res| {
+                    for f in maps.iter() {
+                        f(&mut res);
+                    }
+                    res
+                })) as Box<Future<Item = Response, Error = Box<Error + Send>>>
+            })
+        };
+        CompiledCandidate {
+            guards,
+            check,
+            handler,
+        }
+    }
+}
+
+fn bad_request(message: Cow<'static, str>) -> Response {
+    Response::new()
+        .with_status(StatusCode::BadRequest)
+        .with_body(message.into_owned())
+}
+
+fn call<H: Handler<P>, P>(
+    handler: &H,
+    params: P,
+    info: &RouteInfo,
+    captured: Captured,
+    data: Data,
+    req: Request,
+) -> Box<Future<Item = Response, Error = Box<Error + Send>>> {
+    let fut = handler
+        .call(Ctx {
+            params,
+            route: Arc::clone(&info.route),
+            prefix_params: captured.prefix,
+            raw_params: captured.raw,
+            data: data,
+            request: req,
+        })
+        .into_future()
+        .map_err(|e| Box::new(e) as Box<Error + Send>);
+    Box::new(fut)
+}
+
+struct CompiledCandidate {
+    guards: Vec<Arc<Guard>>,
+    /// Set if the route is skipped when its parameters don't convert.
+    check: Option<MatchCheck>,
+    handler: MatchedHandler,
+}
+
+#[derive(Clone)]
+struct PathRouter(PatternSet, VecMap<Vec<Candidate>>);
+
+impl PathRouter {
+    fn new() -> Self {
+        PathRouter(PatternSet::new(), VecMap::new())
+    }
+
+    fn route(&mut self, pattern: Pattern, candidate: Candidate) -> &mut Self {
+        self.extend(pattern, vec![candidate])
+    }
+
+    fn extend(&mut self, pattern: Pattern, candidates: Vec<Candidate>) -> &mut Self {
+        if let Some(tok) = self.0.get(&pattern) {
+            self.1[tok].extend(candidates);
+        } else {
+            let n = self.1.len();
+            assert_eq!(self.0.insert(pattern), Some(n));
+            self.1.insert(n, candidates);
+        }
+        self
+    }
+
+    fn set(&mut self, pattern: Pattern, candidate: Candidate) -> &mut Self {
+        if let Some(tok) = self.0.get(&pattern) {
+            self.1[tok].clear();
+        }
+        self.route(pattern, candidate)
+    }
+
+    fn compile(self, timer: Option<&Timer>, opts: CompileOptions) -> CompiledPathRouter {
+        let PathRouter(pats, mut candidates) = self;
+        let mut compiled = VecMap::with_capacity(candidates.len());
+        for (pat, &tok) in pats.iter() {
+            let cpat = Arc::new(pat.compile());
+            let cs = candidates.remove(tok).expect("this must not happen");
+            compiled.insert(
+                tok,
+                cs.into_iter()
+                    .map(|c| c.compile(pat, &cpat, timer, opts))
+                    .collect(),
+            );
+        }
+        CompiledPathRouter(pats.compile_with(opts.nfc), compiled, pats)
+    }
+
+    /// Makes all patterns case-insensitive, merging the routes of patterns that become equal.
+    fn fold_case(&mut self) {
+        let PathRouter(pats, mut candidates) = mem::replace(self, PathRouter::new());
+        for (pat, &tok) in pats.iter() {
+            let cs = candidates.remove(tok).expect("this must not happen");
+            let mut pat = pat.clone();
+            pat.set_case_insensitive(true);
+            self.extend(pat, cs);
+        }
+    }
+
+    fn for_each_candidate<F: FnMut(&mut Candidate)>(&mut self, f: &mut F) {
+        for cs in self.1.values_mut() {
+            for c in cs {
+                f(c);
+            }
+        }
+    }
+
+    fn prefix(&self, prefix: &Pattern) -> PathRouter {
+        let n = prefix.parameter_count();
+        let s = prefix.template();
+        let s = s.trim_end_matches('/');
+        let mut candidates = self.1.clone();
+        for cs in candidates.values_mut() {
+            for c in cs {
+                c.prefix_params += n;
+                c.prefix.insert_str(0, s);
+            }
+        }
+        PathRouter(self.0.prefix(prefix), candidates)
+    }
+
+    fn merge(&mut self, other: PathRouter) {
+        let PathRouter(pats, mut candidates) = other;
+        for (pat, &tok) in pats.iter() {
+            let cs = candidates.remove(tok).expect("this must not happen");
+            self.extend(pat.clone(), cs);
+        }
+    }
+}
+
+/// Patterns are kept for `CompiledRouter::explain`.
+struct CompiledPathRouter(
+    CompiledPatternSet,
+    VecMap<Vec<CompiledCandidate>>,
+    PatternSet,
+);
+
+impl CompiledPathRouter {
+    #[inline]
+    fn handler(&self, req: &Request, path: &str) -> Option<RouteHandler> {
+        for m in self.0.matches(path) {
+            for c in &self.1[m.token] {
+                if c.guards.iter().all(|g| g.check(req))
+                    && c.check.as_ref().map_or(true, |check| check(path, &m.captures))
+                {
+                    let handler = Arc::clone(&c.handler);
+                    let captures = m.captures;
+                    return Some(Arc::new(move |req, data, remote_addr| {
+                        handler(req, data, remote_addr, &captures)
+                    }));
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether any route of `pat` accepts `req` to `path`, as `handler` decides. `None` if `pat`
+    /// has no routes here.
+    fn accepts(&self, pat: &Pattern, req: &Request, path: &str) -> Option<Outcome> {
+        let token = self.2.get(pat)?;
+        let cs = &self.1[token];
+        let guarded = cs.iter()
+            .filter(|c| c.guards.iter().all(|g| g.check(req)))
+            .collect::<Vec<_>>();
+        if guarded.is_empty() {
+            return Some(Outcome::Guarded(cs.len()));
+        }
+        let captures = self.0
+            .matches(path)
+            .into_iter()
+            .find(|m| m.token == token)
+            .map(|m| m.captures);
+        let converts = |c: &&CompiledCandidate| match (c.check.as_ref(), captures.as_ref()) {
+            (Some(check), Some(captures)) => check(path, captures),
+            _ => true,
+        };
+        if guarded.iter().any(converts) {
+            Some(Outcome::Matched)
+        } else {
+            Some(Outcome::Unconvertible(guarded.len()))
+        }
+    }
+}
+
+#[test]
+fn test_router() {
+    use std::io;
+
+    let b = Router::new()
+        .route(
+            Method::Get,
             "/foo/bar",
             |_ctx: Ctx| -> io::Result<Response> { Ok(Response::new().with_body("hello!")) },
         )
@@ -205,3 +1517,937 @@ fn test_router() {
     assert!(!b.is_match(&Method::Get, "/foo/bar/"));
     assert!(!b.is_match(&Method::Get, "/foo"));
 }
+
+#[test]
+fn test_guards() {
+    use futures::Future;
+    use guard;
+    use hyper::mime;
+    use util::body_string;
+
+    let b = Router::new()
+        .route_with(
+            Method::Post,
+            "/items",
+            "json",
+            Route::new().guard(guard::ContentType(mime::APPLICATION_JSON)),
+        )
+        .route_with(
+            Method::Post,
+            "/items",
+            "form",
+            Route::new().guard(guard::ContentType(mime::APPLICATION_WWW_FORM_URLENCODED)),
+        )
+        .route_with(
+            Method::Post,
+            "/{name}",
+            |ctx: Ctx<(String,)>| -> Result<Response, ::hyper::Error> {
+                Ok(Response::new().with_body(format!("debug {}", ctx.params.0)))
+            },
+            Route::new().guard(guard::Query::present("debug")),
+        )
+        .route(Method::Post, "/:path", |_ctx: Ctx| -> Result<Response, ::hyper::Error> {
+            Ok(Response::new().with_body("fallback"))
+        })
+        .compile();
+
+    let body = |ct: Option<mime::Mime>, uri: &str| {
+        let mut req = Request::new(Method::Post, uri.parse().unwrap());
+        if let Some(ct) = ct {
+            req.headers_mut().set(::hyper::header::ContentType(ct));
+        }
+        let h = b.handler(&req).unwrap();
+        body_string(h(req, Default::default(), None).wait().unwrap())
+    };
+
+    assert_eq!(body(Some(mime::APPLICATION_JSON), "/items"), "json");
+    assert_eq!(
+        body(Some(mime::APPLICATION_WWW_FORM_URLENCODED), "/items"),
+        "form"
+    );
+    assert_eq!(body(None, "/items?debug"), "debug items");
+    assert_eq!(body(Some(mime::TEXT_PLAIN), "/items"), "fallback");
+}
+
+#[test]
+fn test_route_methods() {
+    use futures::Future;
+    use util::body_string;
+
+    let b = Router::new()
+        .route_methods(&[Method::Get, Method::Post], "/form", "form")
+        .route(Method::Get, "/any", "get")
+        .any("/any", "any")
+        .mount(
+            "/sub",
+            Router::new()
+                .route(Method::Extension("PURGE".to_string()), "/cache", "purge")
+                .any("/any", "sub any"),
+        )
+        .compile();
+
+    let body = |method: Method, path: &str| {
+        let req = Request::new(method, path.parse().unwrap());
+        b.handler(&req)
+            .map(|h| body_string(h(req, Default::default(), None).wait().unwrap()))
+    };
+
+    assert_eq!(body(Method::Get, "/form").unwrap(), "form");
+    assert_eq!(body(Method::Post, "/form").unwrap(), "form");
+    assert!(body(Method::Put, "/form").is_none());
+
+    assert_eq!(body(Method::Get, "/any").unwrap(), "get");
+    assert_eq!(body(Method::Delete, "/any").unwrap(), "any");
+    assert_eq!(body(Method::Extension("LINK".to_string()), "/any").unwrap(), "any");
+
+    let purge = Method::Extension("PURGE".to_string());
+    assert_eq!(body(purge.clone(), "/sub/cache").unwrap(), "purge");
+    assert_eq!(body(purge.clone(), "/any").unwrap(), "any");
+    assert_eq!(body(purge, "/sub/any").unwrap(), "sub any");
+    assert_eq!(body(Method::Patch, "/sub/any").unwrap(), "sub any");
+    assert!(body(Method::Patch, "/sub/cache").is_none());
+}
+
+#[test]
+fn test_scoped_data() {
+    use futures::Future;
+    use util::body_string;
+
+    struct Name(&'static str);
+    struct Global;
+
+    fn name(ctx: Ctx<()>) -> Result<Response, ::hyper::Error> {
+        assert!(ctx.data.get::<Global>().is_some());
+        Ok(Response::new().with_body(ctx.data.get::<Name>().map_or("none", |n| n.0)))
+    }
+
+    let b = Router::new()
+        .route(Method::Get, "/", name)
+        .mount(
+            "/api",
+            Router::new()
+                .route(Method::Get, "/", name)
+                .route_with(Method::Get, "/route", name, Route::new().data(Name("route")))
+                .data(Name("api")),
+        )
+        .compile();
+
+    let mut global = AnyMap::new();
+    global.insert(Global);
+    let global = Arc::new(global);
+    let body = |path: &str| {
+        let req = Request::new(Method::Get, path.parse().unwrap());
+        let h = b.handler(&req).unwrap();
+        body_string(h(req, Arc::clone(&global), None).wait().unwrap())
+    };
+
+    assert_eq!(body("/"), "none");
+    assert_eq!(body("/api/"), "api");
+    assert_eq!(body("/api/route"), "route");
+}
+
+#[test]
+fn test_fallback() {
+    use futures::Future;
+    use util::body_string;
+
+    let b = Router::new()
+        .route(Method::Get, "/", "index")
+        .fallback("not found")
+        .mount(
+            "/api",
+            Router::new()
+                .route(Method::Get, "/items", "items")
+                .fallback("api: not found")
+                .mount("/v1", Router::new().fallback("v1: not found")),
+        )
+        .mount("/static", Router::new().route(Method::Get, "/a", "a"))
+        .compile();
+
+    let body = |method: Method, path: &str| {
+        let req = Request::new(method, path.parse().unwrap());
+        b.handler(&req)
+            .map(|h| body_string(h(req, Default::default(), None).wait().unwrap()))
+    };
+
+    assert_eq!(body(Method::Get, "/").unwrap(), "index");
+    assert_eq!(body(Method::Get, "/foo").unwrap(), "not found");
+    assert_eq!(body(Method::Get, "/api/items").unwrap(), "items");
+    assert_eq!(body(Method::Post, "/api/items").unwrap(), "api: not found");
+    assert_eq!(body(Method::Get, "/api/foo/bar").unwrap(), "api: not found");
+    assert_eq!(body(Method::Get, "/api/v1/foo").unwrap(), "v1: not found");
+    assert_eq!(body(Method::Get, "/static/b").unwrap(), "not found");
+
+    let b = Router::new().route(Method::Get, "/", "index").compile();
+    let req = Request::new(Method::Get, "/foo".parse().unwrap());
+    assert!(b.handler(&req).is_none());
+}
+
+#[test]
+fn test_redirect() {
+    use futures::Future;
+    use hyper::header::Location;
+    use redirect::Redirect;
+
+    let b = Router::new()
+        .route(Method::Get, "/old/{id}", Redirect::moved_permanently("/new/{id}"))
+        .route(Method::Get, "/to/:path", Redirect::found("/{path}"))
+        .route(Method::Get, "/dir/", "dir")
+        .route(Method::Get, "/file", "file")
+        .mount(
+            "/users/{uid}",
+            Router::new()
+                .route(Method::Get, "/old", Redirect::found("/people/{uid}"))
+                .route(Method::Get, "/{uid}/old", Redirect::found("/people/{uid}")),
+        )
+        .redirect_trailing_slash(true)
+        .compile();
+
+    let res = |path: &str| {
+        let req = Request::new(Method::Get, path.parse().unwrap());
+        b.handler(&req)
+            .map(|h| h(req, Default::default(), None).wait().unwrap())
+    };
+    let location = |res: &Response| res.headers().get::<Location>().unwrap().to_string();
+
+    let r = res("/old/42").unwrap();
+    assert_eq!(r.status(), StatusCode::MovedPermanently);
+    assert_eq!(location(&r), "/new/42");
+    let r = res("/old/a%0D%0ASet-Cookie:%20x=1%3Fq").unwrap();
+    assert_eq!(location(&r), "/new/a%0D%0ASet-Cookie:%20x=1%3Fq");
+    let r = res("/to//evil.com").unwrap();
+    assert_eq!(location(&r), "/%2Fevil.com");
+    let r = res("/users/7/old").unwrap();
+    assert_eq!(r.status(), StatusCode::Found);
+    assert_eq!(location(&r), "/people/7");
+    assert_eq!(location(&res("/users/7/8/old").unwrap()), "/people/8");
+
+    let r = res("/dir?a=b").unwrap();
+    assert_eq!(r.status(), StatusCode::PermanentRedirect);
+    assert_eq!(location(&r), "/dir/?a=b");
+
+    let r = res("/file/").unwrap();
+    assert_eq!(r.status(), StatusCode::PermanentRedirect);
+    assert_eq!(location(&r), "/file");
+
+    assert_eq!(res("/dir/").unwrap().status(), StatusCode::Ok);
+    assert!(res("/foo/").is_none());
+}
+
+#[test]
+fn test_parameterized_mount() {
+    use futures::Future;
+    use util::body_string;
+
+    let posts = Router::new()
+        .route(Method::Get, "/", |ctx: Ctx<()>| -> Result<Response, ::hyper::Error> {
+            let uid = ctx.prefix_params.get("uid").unwrap();
+            Ok(Response::new().with_body(format!("posts of {}", uid)))
+        })
+        .route(
+            Method::Get,
+            "/{pid}",
+            |ctx: Ctx<(u32,)>| -> Result<Response, ::hyper::Error> {
+                let (uid,): (u32,) = ctx.prefix_params.parse().unwrap();
+                Ok(Response::new().with_body(format!("post {} of {}", ctx.params.0, uid)))
+            },
+        )
+        .fallback(|ctx: Ctx<()>| -> Result<Response, ::hyper::Error> {
+            let names = ctx.prefix_params.iter().map(|(n, _)| n).collect::<Vec<_>>();
+            Ok(Response::new().with_body(names.join(",")))
+        });
+
+    let b = Router::new()
+        .mount("/users/{uid}", Router::new().mount("/posts", posts))
+        .mount("/orgs/{org}/members/{uid}", Router::new().route(Method::Get, "/", "member"))
+        .compile();
+
+    let body = |path: &str| {
+        let req = Request::new(Method::Get, path.parse().unwrap());
+        let h = b.handler(&req).unwrap();
+        body_string(h(req, Default::default(), None).wait().unwrap())
+    };
+
+    assert_eq!(body("/users/7/posts/"), "posts of 7");
+    assert_eq!(body("/users/7/posts/42"), "post 42 of 7");
+    assert_eq!(body("/users/7/posts/42/comments"), "uid");
+    assert_eq!(body("/orgs/rust/members/7/"), "member");
+}
+
+#[test]
+fn test_timeout() {
+    use futures::Future;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let timed_out = Rc::new(RefCell::new(None));
+    let t = Rc::clone(&timed_out);
+    let b = Router::new()
+        .route_with(
+            Method::Get,
+            "/slow/{n}",
+            |_: Ctx| future::empty::<Response, ::hyper::Error>(),
+            Route::new().timeout(Duration::from_millis(10)),
+        )
+        .route_with(
+            Method::Get,
+            "/fast",
+            "fast",
+            Route::new().timeout_with_status(Duration::from_secs(10), StatusCode::GatewayTimeout),
+        )
+        .on_timeout(move |to| *t.borrow_mut() = Some(to.pattern.clone()))
+        .compile();
+
+    let status = |path: &str| {
+        let req = Request::new(Method::Get, path.parse().unwrap());
+        let h = b.handler(&req).unwrap();
+        h(req, Default::default(), None).wait().unwrap().status()
+    };
+
+    assert_eq!(status("/fast"), StatusCode::Ok);
+    assert!(timed_out.borrow().is_none());
+    assert_eq!(status("/slow/1"), StatusCode::ServiceUnavailable);
+    assert_eq!(timed_out.borrow().as_ref().unwrap(), "/slow/{n}");
+
+    // longer than the default wheel of tokio-timer can wait
+    let timer = tokio_timer::wheel()
+        .tick_duration(Duration::from_millis(1))
+        .build();
+    let b = Router::new()
+        .route_with(
+            Method::Get,
+            "/",
+            move |_: Ctx| timer.sleep(Duration::from_millis(20)).map(|()| Response::new()),
+            Route::new().timeout(Duration::from_secs(600)),
+        )
+        .compile();
+    let req = Request::new(Method::Get, "/".parse().unwrap());
+    let h = b.handler(&req).unwrap();
+    assert_eq!(h(req, Default::default(), None).wait().unwrap().status(), StatusCode::Ok);
+}
+
+#[test]
+fn test_rate_limit() {
+    use futures::Future;
+
+    let limiter = |n| RateLimiter::new(n, Duration::from_secs(60)).key_by_header("X-Api-Key");
+    let b = Router::new()
+        .route_with(
+            Method::Get,
+            "/limited",
+            "limited",
+            Route::new().rate_limit(limiter(1)),
+        )
+        .route(Method::Get, "/other", "other")
+        .rate_limit(limiter(3))
+        .compile();
+
+    let call = |path: &str, key: &'static str| {
+        let mut req = Request::new(Method::Get, path.parse().unwrap());
+        req.headers_mut().set_raw("X-Api-Key", key);
+        let h = b.handler(&req).unwrap();
+        h(req, Default::default(), None).wait().unwrap()
+    };
+    let header = |res: &Response, name: &str| {
+        let raw = res.headers().get_raw(name).unwrap();
+        String::from_utf8(raw.one().unwrap().to_vec()).unwrap()
+    };
+
+    let res = call("/limited", "a");
+    assert_eq!(res.status(), StatusCode::Ok);
+    assert_eq!(header(&res, "RateLimit-Limit"), "1");
+    assert_eq!(header(&res, "RateLimit-Remaining"), "0");
+
+    let res = call("/limited", "a");
+    assert_eq!(res.status(), StatusCode::TooManyRequests);
+    assert_eq!(header(&res, "Retry-After"), "60");
+
+    assert_eq!(call("/limited", "b").status(), StatusCode::Ok);
+
+    // the router-level limit is shared by both routes, but the rejected request didn't use it
+    let res = call("/other", "a");
+    assert_eq!(res.status(), StatusCode::Ok);
+    assert_eq!(header(&res, "RateLimit-Remaining"), "1");
+    assert_eq!(call("/other", "a").status(), StatusCode::Ok);
+    assert_eq!(call("/other", "a").status(), StatusCode::TooManyRequests);
+
+    // clients are keyed by the address the server passes along
+    let b = Router::new()
+        .route(Method::Get, "/", "ok")
+        .rate_limit(RateLimiter::new(1, Duration::from_secs(60)))
+        .compile();
+    let call = |ip: &str| {
+        let req = Request::new(Method::Get, "/".parse().unwrap());
+        let h = b.handler(&req).unwrap();
+        let addr = format!("{}:1234", ip).parse().unwrap();
+        h(req, Default::default(), Some(addr)).wait().unwrap().status()
+    };
+    assert_eq!(call("10.0.0.1"), StatusCode::Ok);
+    assert_eq!(call("10.0.0.1"), StatusCode::TooManyRequests);
+    assert_eq!(call("10.0.0.2"), StatusCode::Ok);
+}
+
+#[test]
+fn test_openapi() {
+    use openapi::Value;
+    use std::io;
+
+    let ok = |_: Ctx| -> io::Result<Response> { Ok(Response::new()) };
+    let r = Router::new()
+        .route_with(
+            Method::Get,
+            "/users/{id}/?",
+            ok,
+            Route::new().doc(
+                Operation::new()
+                    .summary("Get a user")
+                    .tag("users")
+                    .parameter("id", None, Value::schema("integer"))
+                    .response("200", "The user", Some(Value::schema("object"))),
+            ),
+        )
+        .route(Method::Delete, "/users/{id}", ok)
+        .mount("/files", Router::new().route(Method::Get, "/:path", ok))
+        .route(Method::Connect, "/", "connect")
+        .fallback("fallback");
+    let doc = r.openapi(Document::new("Test", "1.0"));
+
+    assert_eq!(
+        doc.to_json(),
+        concat!(
+            r#"{"openapi":"3.0.0","info":{"title":"Test","version":"1.0"},"paths":{"#,
+            r#""/users/{id}":{"delete":{"parameters":[{"name":"id","in":"path","required":true,"#,
+            r#""schema":{"type":"string","minLength":1}}],"responses":{"default":{"description":""}}},"#,
+            r#""get":{"summary":"Get a user","tags":["users"],"#,
+            r#""parameters":[{"name":"id","in":"path","required":true,"schema":{"type":"integer"}}],"#,
+            r#""responses":{"200":{"description":"The user","content":{"application/json":"#,
+            r#"{"schema":{"type":"object"}}}}}}},"#,
+            r#""/files/{path}":{"get":{"parameters":[{"name":"path","in":"path","required":true,"#,
+            r#""description":"Matches the rest of the path, which may contain `/`.","#,
+            r#""schema":{"type":"string"}}],"responses":{"default":{"description":""}}}}}}"#
+        )
+    );
+}
+
+#[test]
+fn test_explain() {
+    use guard;
+    use std::io;
+
+    let ok = |_: Ctx| -> io::Result<Response> { Ok(Response::new()) };
+    let b = Router::new()
+        .route(Method::Get, "/users/{id}", ok)
+        .route(Method::Post, "/users/new", "new")
+        .route(Method::Get, "/users/{id}/posts/", ok)
+        .route_with(
+            Method::Get,
+            "/users/me",
+            "me",
+            Route::new().guard(guard::Header::present("Authorization")),
+        )
+        .fallback("fallback")
+        .compile();
+
+    let e = b.explain(Method::Get, "/users/me").unwrap();
+    let outcomes = e.candidates
+        .iter()
+        .map(|c| (&c.pattern[..], c.outcome.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        outcomes,
+        vec![
+            (
+                "/users/{id}/posts/",
+                Outcome::Mismatch(Mismatch::TooShort),
+            ),
+            ("/users/me", Outcome::Guarded(1)),
+            (
+                "/users/new",
+                Outcome::Mismatch(Mismatch::Segment {
+                    index: 1,
+                    expected: "new".to_string(),
+                    found: "me".to_string(),
+                }),
+            ),
+            ("/users/{id}", Outcome::Matched),
+            ("/:path", Outcome::Matched),
+        ]
+    );
+    assert_eq!(e.winner, Some(3));
+    assert!(e.to_string().contains("=> /users/{id}  "), "{}", e);
+
+    let e = b.explain(Method::Get, "/users/new").unwrap();
+    assert_eq!(
+        e.candidates[2].outcome,
+        Outcome::WrongMethod(vec![Method::Post])
+    );
+    assert_eq!(e.winner, Some(3));
+
+    let e = b.explain(Method::Get, "/users/1/posts").unwrap();
+    assert_eq!(
+        e.candidates[0].outcome,
+        Outcome::Mismatch(Mismatch::MissingTrailingSlash)
+    );
+    assert_eq!(
+        e.candidates[3].outcome,
+        Outcome::Mismatch(Mismatch::TooLong)
+    );
+    assert_eq!(e.winner, Some(4));
+    assert!(e.candidates[4].fallback);
+
+    let e = b.explain(Method::Get, "/users/").unwrap();
+    assert_eq!(
+        e.candidates[3].outcome,
+        Outcome::Mismatch(Mismatch::EmptyParameter {
+            index: 1,
+            name: "id".to_string(),
+        })
+    );
+
+    assert!(b.explain(Method::Get, "/users/1?a=b").is_ok());
+    for path in &["", "users", "*"] {
+        assert_eq!(
+            b.explain(Method::Get, path).err(),
+            Some(InvalidPath(path.to_string()))
+        );
+    }
+
+    // the fallback matches any path, but not these
+    let req = Request::new(Method::Options, "*".parse().unwrap());
+    assert!(b.handler(&req).is_none());
+    assert!(b.explain_request(&req).candidates.is_empty());
+    assert!(!b.is_match(&Method::Get, ""));
+}
+
+#[test]
+fn test_decoding() {
+    use futures::Future;
+    use param::{EncodedSlash, InvalidUtf8};
+    use serve_static::ServeStatic;
+    use std::{fs, io};
+    use util::body_string;
+
+    let dir = ::std::env::temp_dir().join("senya-test-decoding");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("a b.txt"), "spaces").unwrap();
+
+    let router = || {
+        Router::new()
+            .route(
+                Method::Get,
+                "/users/{name}",
+                |ctx: Ctx<(String,)>| -> io::Result<Response> {
+                    let raw = ctx.raw_params.get("name").unwrap();
+                    Ok(Response::new().with_body(format!("{} {}", ctx.params.0, raw)))
+                },
+            )
+            .route(Method::Get, "/static/:path", ServeStatic::new(&dir))
+    };
+    let call = |b: &CompiledRouter, path: &str| {
+        let req = Request::new(Method::Get, path.parse().unwrap());
+        let h = b.handler(&req).unwrap();
+        h(req, Default::default(), None).wait().unwrap()
+    };
+
+    let b = router().compile();
+    assert_eq!(
+        body_string(call(&b, "/users/J%C3%BCrgen")),
+        "Jürgen J%C3%BCrgen"
+    );
+    assert_eq!(body_string(call(&b, "/users/a%2Fb")), "a%2Fb a%2Fb");
+    assert_eq!(call(&b, "/users/%FF").status(), StatusCode::BadRequest);
+    assert_eq!(body_string(call(&b, "/static/a%20b.txt")), "spaces");
+
+    let b = router()
+        .decoding(Decoding {
+            invalid_utf8: InvalidUtf8::Replace,
+            encoded_slash: EncodedSlash::Reject,
+        })
+        .compile();
+    assert_eq!(body_string(call(&b, "/users/%FF")), "\u{FFFD} %FF");
+    assert_eq!(call(&b, "/users/a%2Fb").status(), StatusCode::BadRequest);
+}
+
+#[test]
+fn test_try_route() {
+    let err = Router::new()
+        .try_route(Method::Get, "/users/{id}/{id}", "user")
+        .err()
+        .unwrap();
+    assert_eq!(
+        *err.kind(),
+        PatternErrorKind::DuplicateParameter("id".to_string())
+    );
+    assert_eq!(err.offset(), Some(12));
+
+    let err = Router::new()
+        .try_mount("/api/:rest", Router::new())
+        .err()
+        .unwrap();
+    assert_eq!(
+        *err.kind(),
+        PatternErrorKind::Terminated(":rest".to_string())
+    );
+    assert_eq!(err.offset(), Some(5));
+
+    let b = Router::new()
+        .try_route(Method::Get, "/users", "users")
+        .and_then(|r| r.try_mount("/api", Router::new().route(Method::Get, "/", "api")))
+        .unwrap()
+        .compile();
+    assert!(b.is_match(&Method::Get, "/users"));
+    assert!(b.is_match(&Method::Get, "/api/"));
+}
+
+#[test]
+fn test_wildcards() {
+    use futures::Future;
+    use std::io;
+    use util::body_string;
+
+    let b = Router::new()
+        .route(
+            Method::Get,
+            "/repos/{owner}/**/blob/{file}",
+            |ctx: Ctx<(String, String, String)>| -> io::Result<Response> {
+                let (owner, dir, file) = ctx.params;
+                Ok(Response::new().with_body(format!("{} {} {}", owner, dir, file)))
+            },
+        )
+        .route(
+            Method::Get,
+            "/docs/:path/edit",
+            |ctx: Ctx| -> io::Result<Response> {
+                Ok(Response::new().with_body(ctx.params["path"].clone()))
+            },
+        )
+        .route(Method::Get, "/docs/intro/edit", "fixed")
+        .route(
+            Method::Get,
+            "/docs/{page}/edit",
+            |_: Ctx| -> io::Result<Response> { Ok(Response::new().with_body("parameter")) },
+        )
+        .compile();
+    let call = |path: &str| {
+        let req = Request::new(Method::Get, path.parse().unwrap());
+        let h = b.handler(&req).unwrap();
+        body_string(h(req, Default::default(), None).wait().unwrap())
+    };
+
+    assert_eq!(call("/repos/me/senya/src/blob/lib.rs"), "me senya/src lib.rs");
+    assert_eq!(call("/repos/me/a/blob/blob/b"), "me a/blob b");
+    assert!(!b.is_match(&Method::Get, "/repos/me/blob/lib.rs"));
+    assert_eq!(call("/docs/guide/routing/edit"), "guide/routing");
+    assert_eq!(call("/docs/intro/edit"), "fixed");
+    assert_eq!(call("/docs/guide/edit"), "parameter");
+    assert!(!b.is_match(&Method::Get, "/docs/edit"));
+    assert!(!b.is_match(&Method::Get, "/docs//edit"));
+}
+
+#[test]
+fn test_case_insensitive() {
+    use futures::Future;
+    use std::io;
+    use util::body_string;
+
+    let echo = |ctx: Ctx<(String,)>| -> io::Result<Response> {
+        Ok(Response::new().with_body(ctx.params.0))
+    };
+    let b = Router::new()
+        .route_with(
+            Method::Get,
+            "/Users/{name}",
+            echo,
+            Route::new().case_insensitive(true),
+        )
+        .route(Method::Get, "/users/me", "me")
+        .mount(
+            "/API",
+            Router::new()
+                .route(Method::Get, "/v1/{name}", echo)
+                .case_insensitive(true),
+        )
+        .compile();
+    let call = |path: &str| {
+        let req = Request::new(Method::Get, path.parse().unwrap());
+        let h = b.handler(&req).unwrap();
+        body_string(h(req, Default::default(), None).wait().unwrap())
+    };
+
+    assert_eq!(call("/USERS/JohnDoe"), "JohnDoe");
+    assert_eq!(call("/users/me"), "me");
+    assert_eq!(call("/users/ME"), "ME");
+    assert_eq!(call("/api/V1/Jane"), "Jane");
+    assert!(!b.is_match(&Method::Get, "/Users/me/"));
+
+    let route = |ctx: Ctx| -> io::Result<Response> {
+        let body = format!("{} {}", ctx.route.pattern, ctx.route.case_insensitive);
+        Ok(Response::new().with_body(body))
+    };
+    let b = Router::new()
+        .route_with(Method::Get, "/Users/{id}", route, Route::new().case_insensitive(true))
+        .compile();
+    let req = Request::new(Method::Get, "/users/1".parse().unwrap());
+    let h = b.handler(&req).unwrap();
+    let body = body_string(h(req, Default::default(), None).wait().unwrap());
+    assert_eq!(body, "/Users/{id} true");
+
+    let prefix = |ctx: Ctx| -> io::Result<Response> {
+        Ok(Response::new().with_body(ctx.route.prefix.clone()))
+    };
+    let b = Router::new()
+        .route(Method::Get, "(?i)/Users/{id}", route)
+        .mount(
+            "(?i)/API",
+            Router::new()
+                .route(Method::Get, "/v1/{id}", route)
+                .route(Method::Get, "/prefix", prefix),
+        )
+        .compile();
+    let call = |path: &str| {
+        let req = Request::new(Method::Get, path.parse().unwrap());
+        let h = b.handler(&req).unwrap();
+        body_string(h(req, Default::default(), None).wait().unwrap())
+    };
+    assert_eq!(call("/USERS/1"), "/Users/{id} true");
+    assert_eq!(call("/api/V1/1"), "/API/v1/{id} true");
+    assert_eq!(call("/Api/PREFIX"), "/API");
+}
+
+#[test]
+fn test_optional_groups() {
+    use futures::Future;
+    use param::Optional;
+    use std::io;
+    use util::body_string;
+
+    let b = Router::new()
+        .route(
+            Method::Get,
+            "/archive/{year:u32}[/{month:u32}[/{day:u32}]]",
+            |ctx: Ctx<(u32, Optional<u8>, Optional<u8>)>| -> io::Result<Response> {
+                let (year, month, day) = ctx.params;
+                Ok(Response::new().with_body(format!("{:?}", (year, *month, *day))))
+            },
+        )
+        .route(Method::Get, "/archive/latest", "latest")
+        .compile();
+    let call = |path: &str| {
+        let req = Request::new(Method::Get, path.parse().unwrap());
+        let h = b.handler(&req).unwrap();
+        body_string(h(req, Default::default(), None).wait().unwrap())
+    };
+
+    assert_eq!(call("/archive/2017"), "(2017, None, None)");
+    assert_eq!(call("/archive/2017/5"), "(2017, Some(5), None)");
+    assert_eq!(call("/archive/2017/5/1"), "(2017, Some(5), Some(1))");
+    assert_eq!(call("/archive/latest"), "latest");
+    assert!(!b.is_match(&Method::Get, "/archive/2017/may"));
+}
+
+#[test]
+fn test_normalization() {
+    use futures::Future;
+    use guard;
+    use hyper::header::Location;
+    use std::io;
+    use util::body_string;
+
+    let router = || {
+        Router::new()
+            .route(
+                Method::Get,
+                "/public/{file}",
+                |ctx: Ctx<(String,)>| -> io::Result<Response> {
+                    let body = format!("{} {}", ctx.request.path(), ctx.params.0);
+                    Ok(Response::new().with_body(body))
+                },
+            )
+            .route_with(
+                Method::Get,
+                "/admin/{page}",
+                |_: Ctx| -> io::Result<Response> { Ok(Response::new().with_body("admin")) },
+                Route::new().guard(guard::Header::present("Authorization")),
+            )
+            .fallback("not found")
+    };
+    let res = |b: &CompiledRouter, path: &str| {
+        let req = Request::new(Method::Get, path.parse().unwrap());
+        let h = b.handler(&req).unwrap();
+        h(req, Default::default(), None).wait().unwrap()
+    };
+
+    let b = router().compile();
+    assert_eq!(body_string(res(&b, "/public//a")), "not found");
+
+    let b = router().normalize_paths(Normalization::Rewrite).compile();
+    assert_eq!(body_string(res(&b, "/public//a")), "/public/a a");
+    assert_eq!(body_string(res(&b, "/public/./x/../a?q")), "/public/a a");
+    assert_eq!(body_string(res(&b, "/public/../admin/users")), "not found");
+    assert_eq!(body_string(res(&b, "//admin/users")), "not found");
+    assert!(b.is_match(&Method::Get, "/public/x/../a"));
+    let e = b.explain(Method::Get, "/public/%2e%2e/admin/users").unwrap();
+    let admin = e.candidates.iter().find(|c| c.pattern == "/admin/{page}").unwrap();
+    assert_eq!(admin.outcome, Outcome::Guarded(1));
+    assert_eq!(e.normalized, Some((Normalization::Rewrite, "/admin/users".to_string())));
+
+    let b = router().normalize_paths(Normalization::Redirect).compile();
+    let r = res(&b, "/public/../admin//users?q=1");
+    assert_eq!(r.status(), StatusCode::PermanentRedirect);
+    assert_eq!(r.headers().get::<Location>().unwrap().to_string(), "/admin/users?q=1");
+    assert_eq!(body_string(res(&b, "/public/a")), "/public/a a");
+    assert_eq!(b.explain(Method::Get, "/public/./a").unwrap().winner, None);
+}
+
+#[test]
+fn test_matched_route() {
+    use futures::Future;
+    use std::io;
+    use util::body_string;
+
+    let describe = |ctx: Ctx| -> io::Result<Response> {
+        let r = &ctx.route;
+        let body = format!("{} {:?} {}", r.pattern, r.name, r.prefix);
+        Ok(Response::new().with_body(body))
+    };
+    let users = Router::new()
+        .route_with(Method::Get, "/users/{id}", describe, Route::new().name("user"))
+        .fallback(|ctx: Ctx<()>| -> io::Result<Response> {
+            Ok(Response::new().with_body(ctx.route.pattern.clone()))
+        });
+    let b = Router::new()
+        .route(Method::Get, "/", describe)
+        .mount("/api/{version}", Router::new().mount("/v", users))
+        .compile();
+    let call = |path: &str| {
+        let req = Request::new(Method::Get, path.parse().unwrap());
+        let h = b.handler(&req).unwrap();
+        body_string(h(req, Default::default(), None).wait().unwrap())
+    };
+
+    assert_eq!(call("/"), "/ None ");
+    assert_eq!(
+        call("/api/1/v/users/42"),
+        "/api/{version}/v/users/{id} Some(\"user\") /api/{version}/v"
+    );
+    assert_eq!(call("/api/1/v/posts"), "/api/{version}/v/:path");
+}
+
+#[test]
+fn test_unicode_literals() {
+    use futures::Future;
+    use std::io;
+    use util::body_string;
+
+    let b = Router::new()
+        .route(
+            Method::Get,
+            "/ニュース/{title}",
+            |ctx: Ctx<(String,)>| -> io::Result<Response> {
+                Ok(Response::new().with_body(ctx.params.0))
+            },
+        )
+        .route(Method::Get, "/caf\u{e9}", "café")
+        .normalize_unicode(true)
+        .compile();
+    let call = |path: &str| {
+        let req = Request::new(Method::Get, path.parse().unwrap());
+        let h = b.handler(&req).unwrap();
+        body_string(h(req, Default::default(), None).wait().unwrap())
+    };
+
+    assert_eq!(call("/%E3%83%8B%E3%83%A5%E3%83%BC%E3%82%B9/%E6%97%A5%E6%9C%AC"), "日本");
+    assert_eq!(call("/caf%C3%A9"), "café");
+    assert_eq!(call("/cafe%CC%81"), "café");
+    let e = b.explain(Method::Get, "/cafe%CC%81").unwrap();
+    assert_eq!(e.candidates[e.winner.unwrap()].pattern, "/caf\u{e9}");
+}
+
+#[test]
+fn test_parameter_failure() {
+    use futures::Future;
+    use std::io;
+    use util::body_string;
+
+    let router = || {
+        Router::new()
+            .route(
+                Method::Get,
+                "/items/{id}",
+                |ctx: Ctx<(u8,)>| -> io::Result<Response> {
+                    Ok(Response::new().with_body(format!("item {}", ctx.params.0)))
+                },
+            )
+            .route(
+                Method::Get,
+                "/items/:rest",
+                |ctx: Ctx<(String,)>| -> io::Result<Response> {
+                    Ok(Response::new().with_body(format!("rest {}", ctx.params.0)))
+                },
+            )
+    };
+    let res = |b: &CompiledRouter, path: &str| {
+        let req = Request::new(Method::Get, path.parse().unwrap());
+        let h = b.handler(&req).unwrap();
+        h(req, Default::default(), None).wait().unwrap()
+    };
+
+    let b = router().compile();
+    assert_eq!(body_string(res(&b, "/items/42")), "item 42");
+    let r = res(&b, "/items/abc");
+    assert_eq!(r.status(), StatusCode::BadRequest);
+    assert_eq!(body_string(r), "invalid digit found in string");
+
+    let b = router()
+        .on_parameter_failure(ParameterFailure::FallThrough)
+        .compile();
+    assert_eq!(body_string(res(&b, "/items/42")), "item 42");
+    assert_eq!(body_string(res(&b, "/items/300")), "rest 300");
+    let e = b.explain(Method::Get, "/items/300").unwrap();
+    assert_eq!(e.candidates[0].pattern, "/items/{id}");
+    assert_eq!(e.candidates[0].outcome, Outcome::Unconvertible(1));
+    assert_eq!(e.winner, Some(1));
+    assert_eq!(b.explain(Method::Get, "/items/42").unwrap().winner, Some(0));
+
+    let e = Router::new()
+        .try_route(
+            Method::Get,
+            "/a/{b}/{c}",
+            |_: Ctx<(u32,)>| -> io::Result<Response> { Ok(Response::new()) },
+        )
+        .err()
+        .unwrap();
+    assert_eq!(
+        *e.kind(),
+        PatternErrorKind::ParameterCount {
+            pattern: 2,
+            handler: 1,
+        }
+    );
+    assert_eq!(e.offset(), None);
+    assert_eq!(e.to_string(), "pattern has 2 parameter(s), but the handler takes 1");
+    assert!(Router::new().try_route(Method::Get, "/a/{b}", "static").is_err());
+    assert!(
+        Router::new()
+            .try_route(Method::Get, "/a/{b}", |_: Ctx| -> io::Result<Response> {
+                Ok(Response::new())
+            })
+            .is_ok()
+    );
+    let e = Router::new()
+        .try_route_methods(&[Method::Get, Method::Post], "/a/{b", "static")
+        .err()
+        .unwrap();
+    assert_eq!(e.offset(), Some(3));
+    assert!(
+        Router::new()
+            .try_route_with(Method::Get, "/a/{b}", "static", Route::new().name("a"))
+            .is_err()
+    );
+    assert!(Router::new().try_any("/a//b", "static").is_err());
+    let b = Router::new()
+        .try_any_with("/a", "a", Route::new().case_insensitive(true))
+        .unwrap()
+        .compile();
+    assert!(b.is_match(&Method::Put, "/A"));
+}
@@ -1,37 +1,46 @@
-use futures::Future;
-use hyper::{self, Request, Response};
-use hyper::server::{Http, NewService, Service};
+use futures::{future, Future, Stream};
+use hyper::{self, Request, Response, StatusCode};
+use hyper::server::{Http, Service};
 use router::{CompiledRouter, Router};
-use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use anymap::AnyMap;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Timeout};
 
 // TODO: builder FTW
 
 pub fn serve(addr: SocketAddr, router: Router, data: AnyMap) -> hyper::Result<()> {
     let router = Arc::new(router.compile());
     let data = Arc::new(data);
-    let newsvc = HyperNewService(router, data);
-    let server = Http::new().bind(&addr, newsvc).unwrap();
-    server.run()
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let listener = TcpListener::bind(&addr, &handle)?;
+    let http = Http::<hyper::Chunk>::new();
+    // Accepting connections here rather than in `Http::bind` gives the service the address of
+    // the client, which hyper only exposes through the deprecated `Request::remote_addr`.
+    let server = listener.incoming().then(|conn| {
+        let (socket, remote_addr) = match conn {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Errors such as running out of file descriptors only affect this connection, but
+                // the listener stays ready until they clear, so wait a bit before trying again.
+                eprintln!("failed to accept a connection: {}", e);
+                let pause = Timeout::new(Duration::from_millis(100), &handle);
+                return future::Either::B(future::result(pause).flatten());
+            }
+        };
+        let service = HyperService(Arc::clone(&router), Arc::clone(&data), remote_addr);
+        let conn = http.serve_connection(socket, service).map(|_| ()).map_err(|_| ());
+        handle.spawn(conn);
+        future::Either::A(future::ok(()))
+    });
+    core.run(server.for_each(|()| Ok(())))?;
+    Ok(())
 }
 
-struct HyperNewService(Arc<CompiledRouter>, Arc<AnyMap>);
-
-impl NewService for HyperNewService {
-    type Request = Request;
-    type Response = Response;
-    type Error = hyper::Error;
-    type Instance = HyperService;
-
-    #[inline]
-    fn new_service(&self) -> io::Result<Self::Instance> {
-        Ok(HyperService(Arc::clone(&self.0), Arc::clone(&self.1)))
-    }
-}
-
-struct HyperService(Arc<CompiledRouter>, Arc<AnyMap>);
+struct HyperService(Arc<CompiledRouter>, Arc<AnyMap>, SocketAddr);
 
 impl Service for HyperService {
     type Request = Request;
@@ -46,7 +55,26 @@ impl Service for HyperService {
         //     req.path(),
         //     self.0.is_match(req.method(), req.path())
         // );
-        let h = self.0.handler(req.method(), req.path()).unwrap();
-        Box::new(h(req, Arc::clone(&self.1)).map_err(|e| unimplemented!("error occured: {}", e)))
+        let remote_addr = self.2;
+        if let Some(mo) = self.0.method_override() {
+            let router = Arc::clone(&self.0);
+            let data = Arc::clone(&self.1);
+            Box::new(mo.apply(req).and_then(move |req| dispatch(&router, req, data, remote_addr)))
+        } else {
+            dispatch(&self.0, req, Arc::clone(&self.1), remote_addr)
+        }
     }
 }
+
+fn dispatch(
+    router: &CompiledRouter,
+    req: Request,
+    data: Arc<AnyMap>,
+    remote_addr: SocketAddr,
+) -> Box<Future<Item = Response, Error = hyper::Error>> {
+    let h = match router.handler(&req) {
+        Some(h) => h,
+        None => return Box::new(future::ok(Response::new().with_status(StatusCode::NotFound))),
+    };
+    Box::new(h(req, data, Some(remote_addr)).map_err(|e| unimplemented!("error occured: {}", e)))
+}
@@ -1,4 +1,8 @@
+#[cfg(test)]
+use futures::{Future, Stream};
 use hyper::Method;
+#[cfg(test)]
+use hyper::Response;
 use std::collections::HashMap;
 use std::fmt::{self, Debug, Formatter};
 use std::iter::FromIterator;
@@ -8,6 +12,9 @@ macro_rules! http_method_map {
         http_method_map!($($hyper_method, $name);+);
     };
     ($($hyper_method:tt, $name:ident);+) => {
+        /// Methods other than `Method::Extension`.
+        pub const STANDARD_METHODS: &'static [Method] = &[$(Method::$hyper_method),+];
+
         pub struct HttpMethodMap<T> {
             $($name: Option<T>),+,
             extensions: HashMap<String, T>,
@@ -80,6 +87,26 @@ macro_rules! http_method_map {
                 None
             }
 
+            pub fn for_each_mut<F: FnMut(&Method, &mut T) -> Control<B>, B>(
+                &mut self,
+                mut f: F,
+            ) -> Option<B> {
+                $(
+                    if let Some(val) = self.$name.as_mut() {
+                        if let Control::Break(b) = f(&Method::$hyper_method, val) {
+                            return Some(b);
+                        }
+                    }
+
+                )+
+                for (k, v) in &mut self.extensions {
+                    if let Control::Break(b) = f(&Method::Extension(k.to_string()), v) {
+                        return Some(b);
+                    }
+                }
+                None
+            }
+
             pub fn into_each<F: FnMut(Method, T) -> Control<B>, B>(self, mut f: F) -> Option<B> {
                 $(
                     if let Some(val) = self.$name {
@@ -113,6 +140,15 @@ macro_rules! http_method_map {
             }
         }
 
+        impl<T: Clone> Clone for HttpMethodMap<T> {
+            fn clone(&self) -> Self {
+                HttpMethodMap {
+                    $($name: self.$name.clone()),+,
+                    extensions: self.extensions.clone(),
+                }
+            }
+        }
+
         impl<T: Debug> Debug for HttpMethodMap<T> {
             fn fmt(&self, f: &mut Formatter) -> fmt::Result {
                 let mut d = f.debug_map();
@@ -152,3 +188,9 @@ http_method_map!(
     Connect, connect;
     Patch, patch;
 );
+
+#[cfg(test)]
+pub fn body_string(res: Response) -> String {
+    let body = res.body().concat2().wait().unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
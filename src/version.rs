@@ -0,0 +1,301 @@
+//! Serving several versions of an API, each one a regular `Router`.
+
+use hyper::{Request, Response, StatusCode};
+use router::Router;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// The version a route was registered under, available through `Ctx::data`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiVersion(pub String);
+
+#[derive(Debug)]
+enum Selector {
+    /// `Accept: application/vnd.{vendor}.{version}+json`
+    MediaType(String),
+    Header(Cow<'static, str>),
+    PathPrefix,
+}
+
+impl Selector {
+    fn requested<'a>(&self, req: &'a Request) -> Option<&'a str> {
+        match *self {
+            Selector::MediaType(ref vendor) => {
+                let raw = req.headers().get_raw("Accept")?;
+                raw.iter()
+                    .filter_map(|line| ::std::str::from_utf8(line).ok())
+                    .flat_map(|line| line.split(','))
+                    .filter_map(|range| {
+                        let mut parts = range.split(';');
+                        let essence = parts.next().unwrap_or("").trim();
+                        if parts.any(refuses) {
+                            return None;
+                        }
+                        const PREFIX: &'static str = "application/vnd.";
+                        if !essence.starts_with(PREFIX) {
+                            return None;
+                        }
+                        // stripped once, so that `application/vnd.application/vnd.` isn't taken
+                        let rest = &essence[PREFIX.len()..];
+                        if !rest.starts_with(&vendor[..])
+                            || !rest[vendor.len()..].starts_with('.')
+                        {
+                            return None;
+                        }
+                        let version = &rest[vendor.len() + 1..];
+                        Some(version.split('+').next().unwrap_or(version))
+                    })
+                    .next()
+            }
+            Selector::Header(ref name) => req.headers()
+                .get_raw(name)
+                .and_then(|raw| raw.one())
+                .and_then(|v| ::std::str::from_utf8(v).ok()),
+            Selector::PathPrefix => None,
+        }
+    }
+
+    fn vary(&self) -> Option<&str> {
+        match *self {
+            Selector::MediaType(..) => Some("Accept"),
+            Selector::Header(ref name) => Some(name),
+            Selector::PathPrefix => None,
+        }
+    }
+}
+
+/// Whether a media type parameter is `q=0`, which marks the media type as not acceptable.
+fn refuses(param: &str) -> bool {
+    let mut kv = param.splitn(2, '=');
+    match (kv.next(), kv.next()) {
+        (Some(k), Some(v)) if k.trim().eq_ignore_ascii_case("q") => {
+            v.trim().parse::<f32>().ok() == Some(0.0)
+        }
+        _ => false,
+    }
+}
+
+/// Dispatches to one of several routers by API version. The result of `into_router` can be
+/// mounted like any other router.
+pub struct Versions {
+    selector: Selector,
+    versions: Vec<(String, Router)>,
+    default: Option<String>,
+}
+
+impl Versions {
+    /// Selects by the vendor media type in `Accept`, like `application/vnd.{vendor}.v2+json`.
+    pub fn media_type<V: Into<String>>(vendor: V) -> Self {
+        Self::new(Selector::MediaType(vendor.into()))
+    }
+
+    /// Selects by the value of the header `name`.
+    pub fn header<N: Into<Cow<'static, str>>>(name: N) -> Self {
+        Self::new(Selector::Header(name.into()))
+    }
+
+    /// Mounts each version under `/{version}`. The default version is also mounted at the root.
+    pub fn path_prefix() -> Self {
+        Self::new(Selector::PathPrefix)
+    }
+
+    fn new(selector: Selector) -> Self {
+        Versions {
+            selector,
+            versions: Vec::new(),
+            default: None,
+        }
+    }
+
+    pub fn version<V: Into<String>>(mut self, version: V, router: Router) -> Self {
+        self.versions.push((version.into(), router));
+        self
+    }
+
+    /// Sets the version serving requests that don't ask for one.
+    pub fn default_version<V: Into<String>>(mut self, version: V) -> Self {
+        self.default = Some(version.into());
+        self
+    }
+
+    /// Unless versions are selected by path, every response under the returned router carries
+    /// `Vary`, and requests that no version handles, such as those asking for an unknown version,
+    /// are answered with 404 Not Found.
+    pub fn into_router(self) -> Router {
+        let Versions {
+            selector,
+            versions,
+            default,
+        } = self;
+        let selector = Arc::new(selector);
+
+        if let Some(ref default) = default {
+            assert!(
+                versions.iter().any(|&(ref v, _)| v == default),
+                "unknown default version {:?}",
+                default
+            );
+        }
+
+        let mut out = Router::new();
+        for (version, router) in versions {
+            let is_default = default.as_ref() == Some(&version);
+            let mut router = router.data(ApiVersion(version.clone()));
+
+            if let Selector::PathPrefix = *selector {
+                if is_default {
+                    out = out.merge(router.duplicate());
+                }
+                out = out.mount(&format!("/{}", version), router);
+                continue;
+            }
+
+            let s = Arc::clone(&selector);
+            router = router.guard_all(move |req: &Request| match s.requested(req) {
+                Some(v) => v == version,
+                None => is_default,
+            });
+            out = out.merge(router);
+        }
+
+        if let Some(vary) = selector.vary() {
+            let vary = vary.to_string();
+            let not_found = Router::new().fallback(|_| -> Result<Response, ::hyper::Error> {
+                Ok(Response::new().with_status(StatusCode::NotFound))
+            });
+            out = out.merge(not_found).map_responses(move |res| {
+                res.headers_mut().append_raw("Vary", vary.clone());
+            });
+        }
+        out
+    }
+}
+
+impl From<Versions> for Router {
+    fn from(v: Versions) -> Router {
+        v.into_router()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Ctx;
+    use futures::Future;
+    use hyper::{self, Method, Response};
+    use router::{CompiledRouter, Router};
+    use util::body_string;
+
+    fn version(ctx: Ctx<()>) -> Result<Response, hyper::Error> {
+        let v = ctx.data.get::<ApiVersion>().unwrap();
+        Ok(Response::new().with_body(v.0.clone()))
+    }
+
+    fn versions(v: Versions) -> CompiledRouter {
+        let api = v.version("v1", Router::new().route(Method::Get, "/items", version))
+            .version("v2", Router::new().route(Method::Get, "/items", version))
+            .default_version("v1")
+            .into_router();
+        Router::new().mount("/api", api).compile()
+    }
+
+    /// Returns the body and the `Vary` header.
+    fn call(
+        b: &CompiledRouter,
+        path: &str,
+        header: Option<(&'static str, &'static str)>,
+    ) -> Option<(String, Option<String>)> {
+        let mut req = Request::new(Method::Get, path.parse().unwrap());
+        if let Some((name, value)) = header {
+            req.headers_mut().set_raw(name, value);
+        }
+        let h = b.handler(&req)?;
+        let res = h(req, Default::default(), None).wait().unwrap();
+        let vary = res.headers()
+            .get_raw("Vary")
+            .and_then(|v| v.one())
+            .map(|v| String::from_utf8(v.to_vec()).unwrap());
+        Some((body_string(res), vary))
+    }
+
+    #[test]
+    fn media_type() {
+        let b = versions(Versions::media_type("foo"));
+        let accept = |v| call(&b, "/api/items", Some(("Accept", v))).map(|r| r.0);
+
+        assert_eq!(
+            call(&b, "/api/items", None),
+            Some(("v1".to_string(), Some("Accept".to_string())))
+        );
+        assert_eq!(accept("application/vnd.foo.v2+json").unwrap(), "v2");
+        assert_eq!(
+            accept("text/html, application/vnd.foo.v1+json; q=0.9").unwrap(),
+            "v1"
+        );
+        assert_eq!(accept("application/json").unwrap(), "v1");
+        assert_eq!(accept("application/vnd.foobar.v2+json").unwrap(), "v1");
+        assert_eq!(
+            accept("application/vnd.application/vnd.foo.v2+json").unwrap(),
+            "v1"
+        );
+        assert_eq!(accept("application/vnd.foo.v2+json;q=0").unwrap(), "v1");
+        assert_eq!(
+            accept("application/vnd.foo.v2+json; q=0.0, application/vnd.foo.v1+json").unwrap(),
+            "v1"
+        );
+        // unknown versions get a 404 that varies too
+        assert_eq!(
+            call(&b, "/api/items", Some(("Accept", "application/vnd.foo.v3+json"))),
+            Some((String::new(), Some("Accept".to_string())))
+        );
+    }
+
+    #[test]
+    fn header() {
+        let b = versions(Versions::header("X-Api-Version"));
+
+        assert_eq!(
+            call(&b, "/api/items", Some(("X-Api-Version", "v2"))),
+            Some(("v2".to_string(), Some("X-Api-Version".to_string())))
+        );
+        assert_eq!(call(&b, "/api/items", None).unwrap().0, "v1");
+        assert_eq!(
+            call(&b, "/api/nothing", None),
+            Some((String::new(), Some("X-Api-Version".to_string())))
+        );
+    }
+
+    #[test]
+    fn vary_rejections() {
+        use rate_limit::RateLimiter;
+        use std::time::Duration;
+
+        let limited = Router::new()
+            .route(Method::Get, "/items", version)
+            .rate_limit(RateLimiter::new(1, Duration::from_secs(60)).key_by(|_| Some("".into())));
+        let api = Versions::header("X-Api-Version")
+            .version("v1", limited)
+            .into_router();
+        let b = Router::new().mount("/api", api).compile();
+
+        let header = Some(("X-Api-Version", "v1"));
+        assert_eq!(call(&b, "/api/items", header).unwrap().0, "v1");
+        assert_eq!(
+            call(&b, "/api/items", header),
+            Some(("Too Many Requests".to_string(), Some("X-Api-Version".to_string())))
+        );
+    }
+
+    #[test]
+    fn path_prefix() {
+        let b = versions(Versions::path_prefix());
+
+        assert_eq!(
+            call(&b, "/api/v2/items", None),
+            Some(("v2".to_string(), None))
+        );
+        assert_eq!(call(&b, "/api/v1/items", None).unwrap().0, "v1");
+        assert_eq!(call(&b, "/api/items", None).unwrap().0, "v1");
+        assert!(call(&b, "/api/v3/items", None).is_none());
+    }
+}